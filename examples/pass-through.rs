@@ -15,72 +15,33 @@ type Sample = f32;
 // Routes are the basis of everything in RouteGraph.
 // To create input and to hear output a route needs to be created.
 // This is down by implementing the Route<Sample> trait.
-
-// In order to pass information from the audio backend (in this example Jack)
-// a "context" object is used. This object contains everything that the routes
-// in the graph might need, with each route getting a mutable reference to it.
-//
-// The jack context passes through the process scope as well as references to
-// the input and output ports.
 //
-// Note: due to some lifetime issues with Rust, a bit of unsafe code is needed,
-// This will all be better when GATs are stabilised.
-struct Context {
-    in_l_port: *const jack::Port<jack::AudioIn>,
-    in_r_port: *const jack::Port<jack::AudioIn>,
-
-    out_l_port: *mut jack::Port<jack::AudioOut>,
-    out_r_port: *mut jack::Port<jack::AudioOut>,
-
-    ps: *const jack::ProcessScope,
-}
-
-impl Context {
-    // Get the two buffers of f32 that come from Jack
-    fn get_audio_input(&self) -> [&[f32]; 2] {
-        unsafe {
-            let ps = &*self.ps;
+// Audio from the backend (in this example Jack) is passed into the graph
+// through its input nodes, and the graph's output nodes are copied back
+// out to the backend. Since the context isn't needed to move audio around,
+// it's just ().
 
-            let left = (&*self.in_l_port).as_slice(ps);
-            let right = (&*self.in_r_port).as_slice(ps);
+// Create a route that copies its input straight to its output. It'll be
+// used for both the input and the output of the graph.
+struct PassThroughRoute;
 
-            [left, right]
-        }
-    }
-
-    // Get the two buffers of f32 that Jack will play once we fill
-    // them with audio.
-    fn get_audio_output(&mut self) -> [&mut [f32]; 2] {
-        unsafe {
-            let ps = &*self.ps;
-
-            let left = (&mut *self.out_l_port).as_mut_slice(ps);
-            let right = (&mut *self.out_r_port).as_mut_slice(ps);
-
-            [left, right]
-        }
-    }
-}
-
-// Create a route for input. This recieves the audio from Jack and
-// passes it to our graph.
-struct InputRoute;
-
-// Implement route for the InputRoute
-impl Route<Sample> for InputRoute {
-    type Context = Context;
+// Implement route for the PassThroughRoute
+impl Route<Sample> for PassThroughRoute {
+    type Context = ();
 
     fn process(
         &mut self,
-        _input: &[BufferPoolReference<Sample>],
+        input: &[BufferPoolReference<Sample>],
         output: &mut [BufferPoolReference<Sample>],
-        _frames: usize,
-        context: &mut Self::Context,
+        frames: usize,
+        _context: &mut Self::Context,
     ) {
-        for (output_stream, input_stream) in output.iter_mut().zip(context.get_audio_input().iter())
-        {
-            for (out_sample, in_sample) in
-                output_stream.as_mut().iter_mut().zip(input_stream.iter())
+        for (output_stream, input_stream) in output.iter_mut().zip(input.iter()) {
+            for (out_sample, in_sample) in output_stream
+                .as_mut()
+                .iter_mut()
+                .zip(input_stream.as_ref())
+                .take(frames)
             {
                 *out_sample = *in_sample;
             }
@@ -88,57 +49,6 @@ impl Route<Sample> for InputRoute {
     }
 }
 
-// Much like the InputRoute, create a route so that data can be
-// sent from within the graph back to the outside world.
-struct OutputRoute;
-
-impl Route<Sample> for OutputRoute {
-    type Context = Context;
-
-    fn process(
-        &mut self,
-        input: &[BufferPoolReference<Sample>],
-        _output: &mut [BufferPoolReference<Sample>],
-        _frames: usize,
-        context: &mut Self::Context,
-    ) {
-        for (output_stream, input_stream) in
-            context.get_audio_output().iter_mut().zip(input.as_ref())
-        {
-            for (out_sample, in_sample) in output_stream.iter_mut().zip(input_stream.as_ref()) {
-                *out_sample = *in_sample;
-            }
-        }
-    }
-}
-
-// There's many ways to represent routes inside the graph. To minimise
-// the amount of heap allocations, I've opted to use an enum instead of
-// Box<dyn Route<Sample>>. This also helps if I want to convert the route
-// back into it's original value.
-enum Routes {
-    Input(InputRoute),
-    Output(OutputRoute),
-}
-
-// Likewise, implement Route<Sample> for the Routes enum.
-impl<'a> Route<Sample> for Routes {
-    type Context = Context;
-
-    fn process(
-        &mut self,
-        input: &[BufferPoolReference<Sample>],
-        output: &mut [BufferPoolReference<Sample>],
-        frames: usize,
-        context: &mut Self::Context,
-    ) {
-        match self {
-            Routes::Input(r) => r.process(input, output, frames, context),
-            Routes::Output(r) => r.process(input, output, frames, context),
-        }
-    }
-}
-
 fn main() {
     let client = jack::Client::new(APP_NAME, jack::ClientOptions::NO_START_SERVER)
         .unwrap()
@@ -154,18 +64,23 @@ fn main() {
     // Create the Node to host the route. Nodes have a little bit of extra information
     // that is used with the routing of the graph, such as the number of channels it has
     // and the other nodes that it's connected to.
-    let output = graph
-        .add_node_with_idx(|id| Node::with_id(id, channels, Routes::Output(OutputRoute), vec![]));
+    let output =
+        graph.add_node_with_idx(|id| Node::with_id(id, channels, PassThroughRoute, vec![]));
 
-    graph.add_node_with_idx(|id| {
+    let input = graph.add_node_with_idx(|id| {
         Node::with_id(
             id,
             channels,
-            Routes::Input(InputRoute),
+            PassThroughRoute,
             vec![Connection::new(output.clone(), 1.)],
         )
     });
 
+    // Tell the graph which nodes audio from Jack should go into, and which
+    // nodes should be sent back to Jack.
+    graph.set_inputs(&[input]);
+    graph.set_outputs(&[output]);
+
     graph.topographic_sort();
 
     // Get the specifications for input and output Jack ports
@@ -181,21 +96,15 @@ fn main() {
     let in_r_port = client.register_port(IN_R, in_spec).unwrap();
 
     // Create the Jack callback. This function is called for every buffer that is requested from
-    // Jack. It's responsibility is to send the slices to the input and output routes and then
-    // process the graph.
+    // Jack. It's responsibility is to pass the input and output slices to the graph while it's
+    // processed.
     let process = jack::ClosureProcessHandler::new(
         move |_: &jack::Client, ps: &jack::ProcessScope| -> jack::Control {
-            let frames = ps.n_frames() as usize;
-
-            let mut context = Context {
-                out_r_port: &mut out_r_port,
-                out_l_port: &mut out_l_port,
-                in_l_port: &in_l_port,
-                in_r_port: &in_r_port,
-                ps,
-            };
-
-            graph.process(frames, &mut context);
+            graph.process_with_io(
+                &[in_l_port.as_slice(ps), in_r_port.as_slice(ps)],
+                &mut [out_l_port.as_mut_slice(ps), out_r_port.as_mut_slice(ps)],
+                &mut (),
+            );
 
             jack::Control::Continue
         },
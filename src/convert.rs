@@ -0,0 +1,491 @@
+use crate::route::{Route, Tail};
+use bufferpool::{BufferPool, BufferPoolBuilder, BufferPoolReference};
+use sample::{Sample, I24};
+
+pub(crate) fn to_f64<S: Sample>(sample: S) -> f64 {
+    sample.to_float_sample().to_sample::<f64>()
+}
+
+pub(crate) fn from_f64<S: Sample>(value: f64) -> S {
+    value.to_sample::<S::Float>().to_sample::<S>()
+}
+
+/// The precision of a sample type
+pub trait BitDepth: Sample {
+    const BITS: u32;
+
+    /// Integer samples need to be rounded when converted to, floats don't
+    const INTEGER: bool;
+}
+
+macro_rules! impl_bit_depth {
+    ($($T:ty: $bits:expr, $integer:expr;)*) => {
+        $(
+            impl BitDepth for $T {
+                const BITS: u32 = $bits;
+                const INTEGER: bool = $integer;
+            }
+        )*
+    };
+}
+
+impl_bit_depth! {
+    u8: 8, true;
+    i8: 8, true;
+    i16: 16, true;
+    I24: 24, true;
+    i32: 32, true;
+    f32: 24, false;
+    f64: 53, false;
+}
+
+/// The type of dither applied when reducing the bit depth of a signal
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Dither {
+    None,
+    /// Triangular probability density function dither, one LSB either side
+    Tpdf,
+    /// TPDF dither with first order error feedback, pushing the noise
+    /// up towards nyquist where it's less audible
+    NoiseShaped,
+}
+
+/// Converts buffers between sample types, applying dither when
+/// the output has a lower bit depth than the input.
+pub struct Converter {
+    dither: Dither,
+    seed: u32,
+    errors: Vec<f64>,
+}
+
+impl Converter {
+    pub fn new(dither: Dither, channels: usize) -> Converter {
+        Converter {
+            dither,
+            seed: 0x9E37_79B9,
+            errors: vec![0.; channels],
+        }
+    }
+
+    pub fn dither(&self) -> Dither {
+        self.dither
+    }
+
+    /// Forget the noise shaping error
+    pub fn reset(&mut self) {
+        for error in self.errors.iter_mut() {
+            *error = 0.;
+        }
+    }
+
+    // xorshift, good enough for dither and doesn't allocate
+    fn random(&mut self) -> f64 {
+        let mut x = self.seed;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.seed = x;
+        f64::from(x) / 4_294_967_296.0
+    }
+
+    /// Convert `input` into `output`. The `channel` is used to keep track of
+    /// the error when noise shaping.
+    pub fn convert<A, B>(&mut self, channel: usize, input: &[A], output: &mut [B])
+    where
+        A: BitDepth,
+        B: BitDepth,
+    {
+        if !B::INTEGER || (A::BITS <= B::BITS && A::INTEGER) {
+            for (output, input) in output.iter_mut().zip(input.iter()) {
+                *output = from_f64(to_f64(*input));
+            }
+            return;
+        }
+
+        let step = 2. / 2f64.powi(B::BITS as i32);
+        let dither = if A::BITS > B::BITS {
+            self.dither
+        } else {
+            Dither::None
+        };
+
+        for (output, input) in output.iter_mut().zip(input.iter()) {
+            let value = to_f64(*input);
+
+            let value = match dither {
+                Dither::None => value,
+                Dither::Tpdf => value + (self.random() - self.random()) * step,
+                Dither::NoiseShaped => {
+                    let error = self.errors.get(channel).copied().unwrap_or(0.);
+                    let shaped = value - error;
+                    let noise = (self.random() - self.random()) * step;
+                    let quantised = quantise(shaped + noise, step);
+
+                    if let Some(error) = self.errors.get_mut(channel) {
+                        *error = quantised - shaped;
+                    }
+
+                    *output = from_f64(quantised);
+                    continue;
+                }
+            };
+
+            *output = from_f64(quantise(value, step));
+        }
+    }
+}
+
+fn quantise(value: f64, step: f64) -> f64 {
+    ((value / step).round() * step).max(-1.).min(1. - step)
+}
+
+type Buffers<T> = (
+    BufferPool<T>,
+    Vec<BufferPoolReference<T>>,
+    Vec<BufferPoolReference<T>>,
+);
+
+// Allocate a pool with a buffer for each input and output channel
+fn allocate<T: Sample + Default>(inputs: usize, outputs: usize, buffer_size: usize) -> Buffers<T> {
+    let mut pool = BufferPoolBuilder::new()
+        .with_capacity(inputs + outputs)
+        .with_buffer_size(buffer_size)
+        .build();
+
+    let input = (0..inputs)
+        .map(|_| pool.get_cleared_space().unwrap())
+        .collect();
+    let output = (0..outputs)
+        .map(|_| pool.get_cleared_space().unwrap())
+        .collect();
+
+    (pool, input, output)
+}
+
+/// A route that runs a route of a different sample type, converting
+/// the signal on the way in and out.
+pub struct Converted<S, T: Sample + Default, R> {
+    route: R,
+    channels: usize,
+    sidechain: usize,
+    // The converted inputs followed by the converted sidechain
+    input: Vec<BufferPoolReference<T>>,
+    output: Vec<BufferPoolReference<T>>,
+    to_inner: Converter,
+    from_inner: Converter,
+    // Keep the pool around for as long as the references
+    pool: BufferPool<T>,
+    __type: std::marker::PhantomData<S>,
+}
+
+// The buffer pool references all belong to the pool that's owned
+// by the route, so it's safe to move between threads with it.
+unsafe impl<S, T, R> Send for Converted<S, T, R>
+where
+    T: Sample + Default,
+    R: Send,
+{
+}
+
+impl<S, T, R> Converted<S, T, R>
+where
+    S: BitDepth,
+    T: BitDepth + Default,
+    R: Route<T>,
+{
+    pub fn new(route: R, channels: usize, buffer_size: usize, dither: Dither) -> Self {
+        let (pool, input, output) = allocate(channels, channels, buffer_size);
+
+        Converted {
+            route,
+            channels,
+            sidechain: 0,
+            input,
+            output,
+            to_inner: Converter::new(dither, channels),
+            from_inner: Converter::new(dither, channels),
+            pool,
+            __type: Default::default(),
+        }
+    }
+
+    /// Convert a sidechain with `channels` channels for the inner route
+    pub fn with_sidechain(mut self, channels: usize) -> Self {
+        let buffer_size = self.pool.get_buffer_size();
+
+        self.sidechain = channels;
+        self.to_inner = Converter::new(self.to_inner.dither(), self.channels + channels);
+        self.reallocate(buffer_size);
+        self
+    }
+
+    pub fn route(&mut self) -> &mut R {
+        &mut self.route
+    }
+
+    pub fn into_inner(self) -> R {
+        self.route
+    }
+
+    fn reallocate(&mut self, buffer_size: usize) {
+        // Drop the references before the pool they belong to
+        self.input.clear();
+        self.output.clear();
+
+        let (pool, input, output) =
+            allocate(self.channels + self.sidechain, self.channels, buffer_size);
+
+        self.pool = pool;
+        self.input = input;
+        self.output = output;
+    }
+
+    // Convert the inputs and then the sidechain into the inner buffers
+    fn convert_input(
+        &mut self,
+        input: &[BufferPoolReference<S>],
+        sidechain: &[BufferPoolReference<S>],
+        frames: usize,
+    ) {
+        let channels = self.channels;
+
+        for (channel, inner) in self.input.iter_mut().enumerate() {
+            let inner = inner.as_mut();
+            let frames = frames.min(inner.len());
+
+            let outer = if channel < channels {
+                input.get(channel)
+            } else {
+                sidechain.get(channel - channels)
+            };
+
+            if let Some(outer) = outer {
+                self.to_inner
+                    .convert(channel, &outer.as_ref()[..frames], &mut inner[..frames]);
+            } else {
+                for sample in inner[..frames].iter_mut() {
+                    *sample = T::equilibrium();
+                }
+            }
+        }
+    }
+
+    fn convert_output(&mut self, output: &mut [BufferPoolReference<S>], frames: usize) {
+        for (channel, (output, inner)) in output.iter_mut().zip(self.output.iter()).enumerate() {
+            let output = output.as_mut();
+            let frames = frames.min(output.len());
+
+            self.from_inner
+                .convert(channel, &inner.as_ref()[..frames], &mut output[..frames]);
+        }
+    }
+}
+
+impl<S, T, R, C> Route<S> for Converted<S, T, R>
+where
+    S: BitDepth,
+    T: BitDepth + Default,
+    R: Route<T, Context = C>,
+{
+    type Context = C;
+
+    fn process(
+        &mut self,
+        input: &[BufferPoolReference<S>],
+        output: &mut [BufferPoolReference<S>],
+        frames: usize,
+        context: &mut Self::Context,
+    ) {
+        self.convert_input(input, &[], frames);
+
+        self.route.process(
+            &self.input[..input.len().min(self.channels)],
+            &mut self.output,
+            frames,
+            context,
+        );
+
+        self.convert_output(output, frames);
+    }
+
+    fn process_sidechain(
+        &mut self,
+        input: &[BufferPoolReference<S>],
+        sidechain: &[BufferPoolReference<S>],
+        output: &mut [BufferPoolReference<S>],
+        frames: usize,
+        context: &mut Self::Context,
+    ) {
+        self.convert_input(input, sidechain, frames);
+
+        let (inner, inner_sidechain) = self.input.split_at(self.channels);
+
+        self.route.process_sidechain(
+            &inner[..input.len().min(self.channels)],
+            &inner_sidechain[..sidechain.len().min(self.sidechain)],
+            &mut self.output,
+            frames,
+            context,
+        );
+
+        self.convert_output(output, frames);
+    }
+
+    fn latency(&self) -> usize {
+        self.route.latency()
+    }
+
+    fn tail(&self) -> Tail {
+        self.route.tail()
+    }
+
+    fn prepare(&mut self, sample_rate: f64, max_block: usize, channels: usize) {
+        if max_block > self.pool.get_buffer_size() {
+            self.reallocate(max_block);
+        }
+
+        self.route.prepare(sample_rate, max_block, channels);
+    }
+
+    fn reset(&mut self) {
+        self.to_inner.reset();
+        self.from_inner.reset();
+        self.route.reset();
+    }
+
+    fn release(&mut self) {
+        self.route.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convert_without_dither() {
+        let mut converter = Converter::new(Dither::None, 1);
+
+        let input: [f32; 4] = [0., 0.5, -0.5, 1.];
+        let mut output = [0i16; 4];
+
+        converter.convert(0, &input, &mut output);
+
+        assert_eq!(output, [0, 16_384, -16_384, 32_767]);
+
+        let mut back = [0f32; 4];
+        converter.convert(0, &output, &mut back);
+
+        assert_eq!(back[1], 0.5);
+    }
+
+    struct Invert;
+
+    impl Route<i16> for Invert {
+        type Context = ();
+
+        fn process(
+            &mut self,
+            input: &[BufferPoolReference<i16>],
+            output: &mut [BufferPoolReference<i16>],
+            frames: usize,
+            _context: &mut Self::Context,
+        ) {
+            for (output, input) in output.iter_mut().zip(input.iter()) {
+                for (output, input) in output.as_mut().iter_mut().zip(input.as_ref()).take(frames) {
+                    *output = -*input;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_converted_route() {
+        let mut route: Converted<f32, i16, Invert> = Converted::new(Invert, 1, 8, Dither::None);
+
+        let mut pool: BufferPool<f32> = BufferPoolBuilder::new()
+            .with_capacity(2)
+            .with_buffer_size(8)
+            .build();
+
+        let mut input = pool.get_cleared_space().unwrap();
+        let mut output = [pool.get_cleared_space().unwrap()];
+
+        for sample in input.as_mut().iter_mut() {
+            *sample = 0.5;
+        }
+
+        route.process(&[input], &mut output, 8, &mut ());
+
+        assert_eq!(output[0].as_ref(), &[-0.5; 8]);
+    }
+
+    // Outputs its sidechain
+    struct Key;
+
+    impl Route<i16> for Key {
+        type Context = ();
+
+        fn process(
+            &mut self,
+            input: &[BufferPoolReference<i16>],
+            output: &mut [BufferPoolReference<i16>],
+            frames: usize,
+            context: &mut Self::Context,
+        ) {
+            self.process_sidechain(input, &[], output, frames, context);
+        }
+
+        fn process_sidechain(
+            &mut self,
+            _input: &[BufferPoolReference<i16>],
+            sidechain: &[BufferPoolReference<i16>],
+            output: &mut [BufferPoolReference<i16>],
+            frames: usize,
+            _context: &mut Self::Context,
+        ) {
+            for (i, sample) in output[0].as_mut().iter_mut().take(frames).enumerate() {
+                *sample = sidechain.first().map_or(0, |key| key.as_ref()[i]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_converted_sidechain() {
+        let mut route: Converted<f32, i16, Key> =
+            Converted::new(Key, 1, 8, Dither::None).with_sidechain(1);
+
+        let mut pool: BufferPool<f32> = BufferPoolBuilder::new()
+            .with_capacity(3)
+            .with_buffer_size(8)
+            .build();
+
+        let input = [pool.get_cleared_space().unwrap()];
+        let mut key = [pool.get_cleared_space().unwrap()];
+        let mut output = [pool.get_cleared_space().unwrap()];
+
+        for sample in key[0].as_mut().iter_mut() {
+            *sample = 0.5;
+        }
+
+        route.process_sidechain(&input, &key, &mut output, 8, &mut ());
+
+        assert_eq!(output[0].as_ref(), &[0.5; 8]);
+    }
+
+    #[test]
+    fn test_dither_stays_within_lsb() {
+        for dither in [Dither::Tpdf, Dither::NoiseShaped].iter() {
+            let mut converter = Converter::new(*dither, 1);
+
+            let input = [0.25f64; 256];
+            let mut output = [0u8; 256];
+
+            converter.convert(0, &input, &mut output);
+
+            for sample in output.iter() {
+                assert!((*sample as i32 - 160).abs() <= 2);
+            }
+        }
+    }
+}
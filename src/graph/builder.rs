@@ -1,14 +1,58 @@
-use super::{Route, RouteGraph};
-use generational_arena::Arena;
+use super::{insert_with, sort_positions, Connection, Node, Route, RouteGraph};
+use generational_arena::{Arena, Index};
 use sample::Sample;
-use std::marker::PhantomData;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Reasons a declared graph can't be built
+#[derive(Clone, Debug, PartialEq)]
+pub enum BuildError {
+    /// Two nodes have the same label
+    DuplicateLabel(String),
+    /// An input or output refers to a label that doesn't exist
+    UnknownLabel(String),
+    /// A connection refers to a label that doesn't exist
+    DanglingConnection { source: String, target: String },
+    /// A connection is from an output to an input with a different number
+    /// of channels
+    ChannelMismatch { source: String, target: String },
+    /// The connections contain a cycle through this node
+    Cycle(String),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildError::DuplicateLabel(label) => write!(f, "duplicate label \"{}\"", label),
+            BuildError::UnknownLabel(label) => write!(f, "unknown label \"{}\"", label),
+            BuildError::DanglingConnection { source, target } => write!(
+                f,
+                "connection from \"{}\" to \"{}\" refers to a missing node",
+                source, target
+            ),
+            BuildError::ChannelMismatch { source, target } => write!(
+                f,
+                "the output of \"{}\" and the input of \"{}\" have different numbers of channels",
+                source, target
+            ),
+            BuildError::Cycle(label) => write!(f, "cycle through \"{}\"", label),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
 
 pub struct RouteGraphBuilder<S, R>
 where
     S: Sample + Default,
 {
     buffer_size: usize,
-    __data: PhantomData<(S, R)>,
+    sample_rate: f64,
+    // The label, input channels, output channels and route of each node
+    nodes: Vec<(String, usize, usize, R)>,
+    connections: Vec<(String, String, S)>,
+    inputs: Vec<String>,
+    outputs: Vec<String>,
 }
 
 impl<S, R> RouteGraphBuilder<S, R>
@@ -19,7 +63,11 @@ where
     pub fn new() -> Self {
         Self {
             buffer_size: 1024,
-            __data: Default::default(),
+            sample_rate: 44_100.,
+            nodes: vec![],
+            connections: vec![],
+            inputs: vec![],
+            outputs: vec![],
         }
     }
 
@@ -28,8 +76,144 @@ where
         self
     }
 
+    pub fn with_sample_rate(mut self, sample_rate: f64) -> Self {
+        self.sample_rate = sample_rate;
+        self
+    }
+
+    /// Declare a node, which connections refer to by its label
+    pub fn with_node(self, label: &str, channels: usize, route: R) -> Self {
+        self.with_io_node(label, channels, channels, route)
+    }
+
+    /// Declare a node with different numbers of input and output channels
+    pub fn with_io_node(
+        mut self,
+        label: &str,
+        input_channels: usize,
+        output_channels: usize,
+        route: R,
+    ) -> Self {
+        self.nodes
+            .push((label.to_string(), input_channels, output_channels, route));
+        self
+    }
+
+    pub fn with_connection(mut self, source: &str, target: &str, amount: S) -> Self {
+        self.connections
+            .push((source.to_string(), target.to_string(), amount));
+        self
+    }
+
+    /// Add a node to the graph's inputs, see `RouteGraph::set_inputs`
+    pub fn with_input(mut self, label: &str) -> Self {
+        self.inputs.push(label.to_string());
+        self
+    }
+
+    /// Add a node to the graph's outputs, see `RouteGraph::set_outputs`
+    pub fn with_output(mut self, label: &str) -> Self {
+        self.outputs.push(label.to_string());
+        self
+    }
+
+    /// Check the declared nodes and connections and build a sorted graph
+    /// with pools sized for it.
+    pub fn try_build(self) -> Result<RouteGraph<S, R>, BuildError> {
+        let mut positions: HashMap<String, usize> = HashMap::with_capacity(self.nodes.len());
+
+        for (i, (label, _, _, _)) in self.nodes.iter().enumerate() {
+            if positions.insert(label.clone(), i).is_some() {
+                return Err(BuildError::DuplicateLabel(label.clone()));
+            }
+        }
+
+        let mut edges: Vec<Vec<usize>> = vec![vec![]; self.nodes.len()];
+
+        for (source, target, _) in self.connections.iter() {
+            match (
+                positions.get(source.as_str()),
+                positions.get(target.as_str()),
+            ) {
+                (Some(from), Some(to)) => {
+                    if self.nodes[*from].2 != self.nodes[*to].1 {
+                        return Err(BuildError::ChannelMismatch {
+                            source: source.clone(),
+                            target: target.clone(),
+                        });
+                    }
+
+                    edges[*from].push(*to);
+                }
+                _ => {
+                    return Err(BuildError::DanglingConnection {
+                        source: source.clone(),
+                        target: target.clone(),
+                    })
+                }
+            }
+        }
+
+        for label in self.inputs.iter().chain(self.outputs.iter()) {
+            if !positions.contains_key(label.as_str()) {
+                return Err(BuildError::UnknownLabel(label.clone()));
+            }
+        }
+
+        if let Err(i) = sort_positions(&edges) {
+            return Err(BuildError::Cycle(self.nodes[i].0.clone()));
+        }
+
+        let mut arena = Arena::with_capacity(self.nodes.len());
+        let mut ids: Vec<Index> = Vec::with_capacity(self.nodes.len());
+
+        for (label, input_channels, output_channels, route) in self.nodes {
+            let id = insert_with(&mut arena, |id| {
+                let mut node = Node::with_id(id, input_channels, route, vec![])
+                    .with_output_channels(output_channels);
+                node.label = Some(label);
+                node
+            });
+
+            ids.push(id);
+        }
+
+        for (source, target, amount) in self.connections.iter() {
+            let target = ids[positions[target.as_str()]];
+
+            if let Some(node) = arena.get_mut(ids[positions[source.as_str()]]) {
+                match node.connections.iter_mut().find(|c| c.id == target) {
+                    Some(connection) => connection.amount = *amount,
+                    None => node.connections.push(Connection::new(target, *amount)),
+                }
+            }
+        }
+
+        let terminals = |labels: &[String]| -> Vec<Index> {
+            labels
+                .iter()
+                .map(|label| ids[positions[label.as_str()]])
+                .collect()
+        };
+
+        let inputs = terminals(&self.inputs);
+        let outputs = terminals(&self.outputs);
+
+        let mut graph = RouteGraph::build(arena, self.buffer_size, self.sample_rate);
+
+        graph.set_inputs(&inputs);
+        graph.set_outputs(&outputs);
+
+        Ok(graph)
+    }
+
+    /// # Panics
+    /// If the declared nodes and connections aren't valid, see `try_build`
     pub fn build(self) -> RouteGraph<S, R> {
-        RouteGraph::build(Arena::new(), self.buffer_size)
+        match self.try_build() {
+            Ok(graph) => graph,
+            Err(error) => panic!("Invalid graph: {}", error),
+        }
     }
 }
 
@@ -0,0 +1,71 @@
+use crate::convert::{from_f64, to_f64};
+use sample::Sample;
+
+/// Which topology a signal belongs to while one is crossfaded into another
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Side {
+    /// Faded out with the old topology
+    Old,
+    /// Faded in with the new topology
+    New,
+    /// Left alone, because it's in both or has already been faded
+    Both,
+}
+
+/// A linear fade in or out over a number of frames
+pub(crate) struct Fade {
+    frames: usize,
+    position: usize,
+    fading_in: bool,
+}
+
+impl Fade {
+    pub(crate) fn new(frames: usize, fading_in: bool) -> Fade {
+        Fade {
+            frames,
+            position: 0,
+            fading_in,
+        }
+    }
+
+    pub(crate) fn is_fading_in(&self) -> bool {
+        self.fading_in
+    }
+
+    pub(crate) fn is_finished(&self) -> bool {
+        self.position >= self.frames
+    }
+
+    fn gain(&self, frame: usize) -> f64 {
+        let progress = if self.frames == 0 {
+            1.
+        } else {
+            (self.position + frame).min(self.frames) as f64 / self.frames as f64
+        };
+
+        if self.fading_in {
+            progress
+        } else {
+            1. - progress
+        }
+    }
+
+    /// Scale a sample `frame` frames into the current block
+    pub(crate) fn apply<S: Sample>(&self, sample: S, frame: usize) -> S {
+        from_f64(to_f64(sample) * self.gain(frame))
+    }
+
+    /// Scale a sample by the gain of its side of a crossfade that's
+    /// fading in the new side
+    pub(crate) fn apply_side<S: Sample>(&self, side: Side, sample: S, frame: usize) -> S {
+        match side {
+            Side::Old => from_f64(to_f64(sample) * (1. - self.gain(frame))),
+            Side::New => self.apply(sample, frame),
+            Side::Both => sample,
+        }
+    }
+
+    pub(crate) fn advance(&mut self, frames: usize) {
+        self.position += frames;
+    }
+}
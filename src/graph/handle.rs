@@ -0,0 +1,243 @@
+use super::{Node, RouteGraph};
+use crate::route::Route;
+use generational_arena::Index;
+use sample::Sample;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static NEXT_GRAPH_ID: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) fn next_graph_id() -> usize {
+    NEXT_GRAPH_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// A handle to a node in a graph of type `G`. Handles also remember which
+/// graph they came from and every call that takes one checks it, so using
+/// one with a different graph of the same type panics instead of quietly
+/// referring to the wrong node.
+///
+/// Handles are how connections are made and removed on a `RouteGraph` and
+/// in a `History`, where mixing up the source and target is easy. Calls
+/// that work on a single node, and `TopologyBuilder`, still take the
+/// node's `Index`, which `index` returns.
+pub struct NodeId<G> {
+    index: Index,
+    graph: usize,
+    __graph: PhantomData<fn() -> G>,
+}
+
+impl<G> NodeId<G> {
+    pub fn index(&self) -> Index {
+        self.index
+    }
+
+    /// The node's first output port, for connecting it to other nodes
+    pub fn output(&self) -> OutputPort<G> {
+        OutputPort {
+            node: *self,
+            port: 0,
+        }
+    }
+
+    /// The node's first input port, for connecting other nodes to it
+    pub fn input(&self) -> InputPort<G> {
+        InputPort {
+            node: *self,
+            port: 0,
+        }
+    }
+}
+
+// These are implemented by hand so that `G` doesn't need to implement them
+
+impl<G> Clone for NodeId<G> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<G> Copy for NodeId<G> {}
+
+impl<G> PartialEq for NodeId<G> {
+    fn eq(&self, other: &Self) -> bool {
+        self.index == other.index && self.graph == other.graph
+    }
+}
+
+impl<G> Eq for NodeId<G> {}
+
+impl<G> Hash for NodeId<G> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.index.hash(state);
+        self.graph.hash(state);
+    }
+}
+
+impl<G> fmt::Debug for NodeId<G> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("NodeId")
+            .field("index", &self.index)
+            .field("graph", &self.graph)
+            .finish()
+    }
+}
+
+/// The end of a node that signal leaves from
+pub struct OutputPort<G> {
+    node: NodeId<G>,
+    port: usize,
+}
+
+impl<G> OutputPort<G> {
+    pub fn node(&self) -> NodeId<G> {
+        self.node
+    }
+
+    /// The position of the port on its node
+    pub fn port(&self) -> usize {
+        self.port
+    }
+}
+
+impl<G> Clone for OutputPort<G> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<G> Copy for OutputPort<G> {}
+
+impl<G> fmt::Debug for OutputPort<G> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("OutputPort")
+            .field("node", &self.node)
+            .field("port", &self.port)
+            .finish()
+    }
+}
+
+/// The end of a node that signal arrives at
+pub struct InputPort<G> {
+    node: NodeId<G>,
+    port: usize,
+}
+
+impl<G> InputPort<G> {
+    pub fn node(&self) -> NodeId<G> {
+        self.node
+    }
+
+    /// The position of the port on its node
+    pub fn port(&self) -> usize {
+        self.port
+    }
+}
+
+impl<G> Clone for InputPort<G> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<G> Copy for InputPort<G> {}
+
+impl<G> fmt::Debug for InputPort<G> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("InputPort")
+            .field("node", &self.node)
+            .field("port", &self.port)
+            .finish()
+    }
+}
+
+impl<S, R, C> RouteGraph<S, R>
+where
+    S: Sample + Default,
+    R: Route<S, Context = C>,
+{
+    // Check that a handle came from this graph
+    pub(crate) fn resolve(&self, id: NodeId<Self>) -> Index {
+        assert_eq!(
+            id.graph, self.graph_id,
+            "Node handle belongs to a different graph!"
+        );
+
+        id.index
+    }
+
+    /// Get a handle for a node in this graph
+    pub fn node_id(&self, index: Index) -> Option<NodeId<Self>> {
+        if self.arena.contains(index) {
+            Some(NodeId {
+                index,
+                graph: self.graph_id,
+                __graph: PhantomData,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Whether the node is in this graph. Handles from other graphs are
+    /// never contained.
+    pub fn contains(&self, id: NodeId<Self>) -> bool {
+        id.graph == self.graph_id && self.arena.contains(id.index)
+    }
+
+    /// Add a node and return a handle to it
+    pub fn add_node<F: Send + FnMut(Index) -> Node<S, R>>(&mut self, func: F) -> NodeId<Self> {
+        let index = self.add_node_with_idx(func);
+
+        NodeId {
+            index,
+            graph: self.graph_id,
+            __graph: PhantomData,
+        }
+    }
+
+    /// The output port of a node called `name`
+    ///
+    /// # Panics
+    /// If the handle belongs to a different graph
+    pub fn output_port(&self, id: NodeId<Self>, name: &str) -> Option<OutputPort<Self>> {
+        let port = self.with_node(self.resolve(id), |node| node.output_port(name))??;
+        Some(OutputPort { node: id, port })
+    }
+
+    /// The input port of a node called `name`
+    ///
+    /// # Panics
+    /// If the handle belongs to a different graph
+    pub fn input_port(&self, id: NodeId<Self>, name: &str) -> Option<InputPort<Self>> {
+        let port = self.with_node(self.resolve(id), |node| node.input_port(name))??;
+        Some(InputPort { node: id, port })
+    }
+
+    /// # Panics
+    /// If the handle belongs to a different graph
+    pub fn remove(&mut self, id: NodeId<Self>) -> Option<Node<S, R>> {
+        let index = self.resolve(id);
+        self.remove_node(index)
+    }
+
+    /// Connect an output to an input, or change the amount if they're
+    /// already connected.
+    ///
+    /// # Panics
+    /// If either port belongs to a different graph
+    pub fn connect(&mut self, source: OutputPort<Self>, target: InputPort<Self>, amount: S) {
+        let source_node = self.resolve(source.node);
+        let target_node = self.resolve(target.node);
+
+        self.set_connection_amount(source_node, source.port, target_node, target.port, amount);
+        self.sorted = false;
+    }
+
+    /// # Panics
+    /// If either port belongs to a different graph
+    pub fn disconnect(&mut self, source: OutputPort<Self>, target: InputPort<Self>) {
+        self.connect(source, target, S::equilibrium());
+    }
+}
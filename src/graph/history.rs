@@ -0,0 +1,375 @@
+use super::{insert_with, InputPort, Node, OutputPort, RouteGraph};
+use crate::route::Route;
+use generational_arena::Index;
+use sample::Sample;
+use std::collections::HashMap;
+
+// A node that's been taken out of the graph, along with everything needed
+// to put it back
+struct Removed<S, R> {
+    node: Node<S, R>,
+    // The source, amount, source port and target port of each connection
+    // to the node
+    incoming: Vec<(Index, S, usize, usize)>,
+    // Where the node was in the graph's inputs and outputs
+    input: Option<usize>,
+    output: Option<usize>,
+}
+
+// The amount of the connection between two ports, which is equilibrium if
+// they aren't connected
+fn connection_amount<S, R, C>(
+    graph: &RouteGraph<S, R>,
+    source: Index,
+    source_port: usize,
+    target: Index,
+    target_port: usize,
+) -> S
+where
+    S: Sample + Default,
+    R: Route<S, Context = C>,
+{
+    graph
+        .with_node(source, |node| {
+            node.connections
+                .iter()
+                .find(|c| c.is_between(target, source_port, target_port))
+                .map(|connection| connection.amount)
+        })
+        .flatten()
+        .unwrap_or_else(S::equilibrium)
+}
+
+type NodeChange<S, R> = Box<dyn FnMut(&mut Node<S, R>)>;
+
+enum Edit<S, R> {
+    // The node is held here while adding it is undone
+    Add(Index, Option<Removed<S, R>>),
+    // The node is held here until removing it is undone
+    Remove(Index, Option<Removed<S, R>>),
+    Amount {
+        source: Index,
+        source_port: usize,
+        target: Index,
+        target_port: usize,
+        before: S,
+        after: S,
+    },
+    Change(Index, NodeChange<S, R>, NodeChange<S, R>),
+}
+
+/// A record of edits made to a graph that can be undone and redone.
+///
+/// Edits made through the history are recorded, edits made to the graph
+/// directly aren't. Like editing the graph directly, the graph needs to be
+/// sorted again after adding or connecting nodes.
+///
+/// A node that's restored by undoing its removal gets a new index, so use
+/// `current` to find the index of a node that's been removed and restored.
+pub struct History<S, R> {
+    undo: Vec<Edit<S, R>>,
+    redo: Vec<Edit<S, R>>,
+    moved: HashMap<Index, Index>,
+}
+
+impl<S, R, C> History<S, R>
+where
+    S: Sample + Default,
+    R: Route<S, Context = C>,
+{
+    pub fn new() -> Self {
+        History {
+            undo: vec![],
+            redo: vec![],
+            moved: HashMap::new(),
+        }
+    }
+
+    /// The index a node has now, following it through any removals that
+    /// have been undone
+    pub fn current(&self, mut id: Index) -> Index {
+        while let Some(next) = self.moved.get(&id) {
+            id = *next;
+        }
+
+        id
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    /// Forget every edit, dropping any removed nodes
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.moved.clear();
+    }
+
+    fn record(&mut self, edit: Edit<S, R>) {
+        self.undo.push(edit);
+        self.redo.clear();
+    }
+
+    pub fn add_node<F: Send + FnMut(Index) -> Node<S, R>>(
+        &mut self,
+        graph: &mut RouteGraph<S, R>,
+        func: F,
+    ) -> Index {
+        let id = graph.add_node_with_idx(func);
+        self.record(Edit::Add(id, None));
+        id
+    }
+
+    /// Remove a node, keeping it and its connections so the removal can be
+    /// undone. Returns false if the node isn't in the graph.
+    pub fn remove_node(&mut self, graph: &mut RouteGraph<S, R>, id: Index) -> bool {
+        match self.take(graph, id) {
+            Some(removed) => {
+                self.record(Edit::Remove(id, Some(removed)));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Connect an output to an input, change the amount of their connection
+    /// or disconnect them, see `RouteGraph::connect`
+    ///
+    /// # Panics
+    /// If either port belongs to a different graph
+    pub fn connect(
+        &mut self,
+        graph: &mut RouteGraph<S, R>,
+        source: OutputPort<RouteGraph<S, R>>,
+        target: InputPort<RouteGraph<S, R>>,
+        amount: S,
+    ) {
+        let (source_port, target_port) = (source.port(), target.port());
+        let source = graph.resolve(source.node());
+        let target = graph.resolve(target.node());
+
+        let before = connection_amount(graph, source, source_port, target, target_port);
+
+        graph.set_connection_amount(source, source_port, target, target_port, amount);
+        graph.sorted = false;
+
+        let after = connection_amount(graph, source, source_port, target, target_port);
+
+        // Nothing to record if the ports weren't valid
+        if before != after {
+            self.record(Edit::Amount {
+                source,
+                source_port,
+                target,
+                target_port,
+                before,
+                after,
+            });
+        }
+    }
+
+    /// # Panics
+    /// If either port belongs to a different graph
+    pub fn disconnect(
+        &mut self,
+        graph: &mut RouteGraph<S, R>,
+        source: OutputPort<RouteGraph<S, R>>,
+        target: InputPort<RouteGraph<S, R>>,
+    ) {
+        self.connect(graph, source, target, S::equilibrium());
+    }
+
+    /// Change something about a node, like one of its route's parameters,
+    /// with `apply`. Undoing the change calls `revert`, so it should put
+    /// things back the way they were before `apply` was called.
+    pub fn change<A, V>(&mut self, graph: &mut RouteGraph<S, R>, id: Index, mut apply: A, revert: V)
+    where
+        A: FnMut(&mut Node<S, R>) + 'static,
+        V: FnMut(&mut Node<S, R>) + 'static,
+    {
+        if graph.with_node_mut(id, |node| apply(node)).is_some() {
+            self.record(Edit::Change(id, Box::new(apply), Box::new(revert)));
+        }
+    }
+
+    /// Undo the last edit. Returns false if there was nothing to undo.
+    pub fn undo(&mut self, graph: &mut RouteGraph<S, R>) -> bool {
+        match self.undo.pop() {
+            Some(edit) => {
+                let edit = self.apply(graph, edit, false);
+                self.redo.push(edit);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Redo the last edit that was undone. Returns false if there was
+    /// nothing to redo.
+    pub fn redo(&mut self, graph: &mut RouteGraph<S, R>) -> bool {
+        match self.redo.pop() {
+            Some(edit) => {
+                let edit = self.apply(graph, edit, true);
+                self.undo.push(edit);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Make an edit again, or undo it, returning the edit so it can be
+    // moved to the other stack
+    fn apply(&mut self, graph: &mut RouteGraph<S, R>, edit: Edit<S, R>, redo: bool) -> Edit<S, R> {
+        match edit {
+            Edit::Add(id, removed) => Edit::Add(id, self.toggle(graph, id, removed, redo)),
+            Edit::Remove(id, removed) => Edit::Remove(id, self.toggle(graph, id, removed, !redo)),
+            Edit::Amount {
+                source,
+                source_port,
+                target,
+                target_port,
+                before,
+                after,
+            } => {
+                graph.set_connection_amount(
+                    self.current(source),
+                    source_port,
+                    self.current(target),
+                    target_port,
+                    if redo { after } else { before },
+                );
+                graph.sorted = false;
+
+                edit
+            }
+            Edit::Change(id, mut apply, mut revert) => {
+                graph.with_node_mut(self.current(id), |node| {
+                    if redo {
+                        apply(node)
+                    } else {
+                        revert(node)
+                    }
+                });
+
+                Edit::Change(id, apply, revert)
+            }
+        }
+    }
+
+    // Put a removed node back if `restore` is set, otherwise take it out
+    fn toggle(
+        &mut self,
+        graph: &mut RouteGraph<S, R>,
+        id: Index,
+        removed: Option<Removed<S, R>>,
+        restore: bool,
+    ) -> Option<Removed<S, R>> {
+        let id = self.current(id);
+
+        if restore {
+            if let Some(removed) = removed {
+                let restored = self.restore(graph, removed);
+                self.moved.insert(id, restored);
+            }
+
+            None
+        } else {
+            self.take(graph, id)
+        }
+    }
+
+    fn take(&self, graph: &mut RouteGraph<S, R>, id: Index) -> Option<Removed<S, R>> {
+        let mut sources: Vec<Index> = graph.inputs_of(id).collect();
+        sources.sort();
+        sources.dedup();
+
+        let incoming = sources
+            .into_iter()
+            .flat_map(|source| {
+                graph.arena[source]
+                    .connections
+                    .iter()
+                    .filter(|connection| connection.id == id)
+                    .map(move |connection| {
+                        (
+                            source,
+                            connection.amount,
+                            connection.source_port,
+                            connection.target_port,
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+
+        let input = graph.inputs.iter().position(|input| *input == id);
+        let output = graph.outputs.iter().position(|output| *output == id);
+
+        graph.remove_node(id).map(|node| Removed {
+            node,
+            incoming,
+            input,
+            output,
+        })
+    }
+
+    fn restore(&mut self, graph: &mut RouteGraph<S, R>, removed: Removed<S, R>) -> Index {
+        let Removed {
+            mut node,
+            incoming,
+            input,
+            output,
+        } = removed;
+
+        // Connections to nodes that have been removed since are dropped
+        for connection in node.connections.iter_mut() {
+            connection.id = self.current(connection.id);
+        }
+
+        node.connections
+            .retain(|connection| graph.arena.contains(connection.id));
+        node.incoming.clear();
+        node.buffers.clear();
+
+        let id = insert_with(&mut graph.arena, |id| {
+            node.id = id;
+            node
+        });
+
+        graph.finish_adding_node(id);
+
+        for (source, amount, source_port, target_port) in incoming {
+            graph.set_connection_amount(self.current(source), source_port, id, target_port, amount);
+        }
+
+        // Terminals keep their place, so the host's channels line up again
+        if let Some(position) = input {
+            let mut inputs = graph.inputs.clone();
+            inputs.insert(position.min(inputs.len()), id);
+            graph.set_inputs(&inputs);
+        }
+
+        if let Some(position) = output {
+            let mut outputs = graph.outputs.clone();
+            outputs.insert(position.min(outputs.len()), id);
+            graph.set_outputs(&outputs);
+        }
+
+        id
+    }
+}
+
+impl<S, R, C> Default for History<S, R>
+where
+    S: Sample + Default,
+    R: Route<S, Context = C>,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,385 @@
+use crate::convert::to_f64;
+use bufferpool::BufferPoolReference;
+use sample::Sample;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+// Loudness is measured in 100ms blocks. Momentary loudness covers 4 blocks
+// and short term loudness covers 30.
+const BLOCKS: usize = 30;
+const MOMENTARY_BLOCKS: usize = 4;
+
+// Gating blocks are stored in a histogram from -70 LUFS in 0.1 LU steps
+// so integrated loudness can be measured without allocating.
+const HISTOGRAM_BINS: usize = 1000;
+const ABSOLUTE_GATE: f64 = -70.;
+const RELATIVE_GATE: f64 = -10.;
+
+// True peak is measured by oversampling 4 times with a 48 tap filter
+const TRUE_PEAK_PHASES: usize = 4;
+const TRUE_PEAK_TAPS: usize = 12;
+
+fn loudness(mean_square: f64) -> f64 {
+    if mean_square > 0. {
+        -0.691 + 10. * mean_square.log10()
+    } else {
+        f64::NEG_INFINITY
+    }
+}
+
+fn mean_square(loudness: f64) -> f64 {
+    10f64.powf((loudness + 0.691) / 10.)
+}
+
+struct AtomicF64(AtomicU64);
+
+impl AtomicF64 {
+    fn new(value: f64) -> AtomicF64 {
+        AtomicF64(AtomicU64::new(value.to_bits()))
+    }
+
+    fn load(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    fn store(&self, value: f64) {
+        self.0.store(value.to_bits(), Ordering::Relaxed)
+    }
+}
+
+struct ChannelReadings {
+    peak: AtomicF64,
+    rms: AtomicF64,
+    true_peak: AtomicF64,
+    momentary: AtomicF64,
+    short_term: AtomicF64,
+    integrated: AtomicF64,
+}
+
+impl ChannelReadings {
+    fn new() -> ChannelReadings {
+        ChannelReadings {
+            peak: AtomicF64::new(0.),
+            rms: AtomicF64::new(0.),
+            true_peak: AtomicF64::new(0.),
+            momentary: AtomicF64::new(f64::NEG_INFINITY),
+            short_term: AtomicF64::new(f64::NEG_INFINITY),
+            integrated: AtomicF64::new(f64::NEG_INFINITY),
+        }
+    }
+}
+
+/// The latest levels from a meter, published from the audio thread.
+///
+/// Peak, RMS and true peak are linear amplitudes measured over the last
+/// processed block. Loudness is in LUFS, following EBU R128.
+pub struct MeterReadings {
+    channels: Box<[ChannelReadings]>,
+}
+
+impl MeterReadings {
+    fn channel(&self, channel: usize) -> Option<&ChannelReadings> {
+        self.channels.get(channel)
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels.len()
+    }
+
+    pub fn peak(&self, channel: usize) -> f64 {
+        self.channel(channel).map(|c| c.peak.load()).unwrap_or(0.)
+    }
+
+    pub fn rms(&self, channel: usize) -> f64 {
+        self.channel(channel).map(|c| c.rms.load()).unwrap_or(0.)
+    }
+
+    pub fn true_peak(&self, channel: usize) -> f64 {
+        self.channel(channel)
+            .map(|c| c.true_peak.load())
+            .unwrap_or(0.)
+    }
+
+    /// Loudness over the last 400ms
+    pub fn momentary(&self, channel: usize) -> f64 {
+        self.channel(channel)
+            .map(|c| c.momentary.load())
+            .unwrap_or(f64::NEG_INFINITY)
+    }
+
+    /// Loudness over the last 3s
+    pub fn short_term(&self, channel: usize) -> f64 {
+        self.channel(channel)
+            .map(|c| c.short_term.load())
+            .unwrap_or(f64::NEG_INFINITY)
+    }
+
+    /// Gated loudness since the meter was attached
+    pub fn integrated(&self, channel: usize) -> f64 {
+        self.channel(channel)
+            .map(|c| c.integrated.load())
+            .unwrap_or(f64::NEG_INFINITY)
+    }
+}
+
+#[derive(Clone, Default)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 3],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, input: f64) -> f64 {
+        let output = self.b[0] * input + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[1] * self.y[0]
+            - self.a[2] * self.y[1];
+
+        self.x = [input, self.x[0]];
+        self.y = [output, self.y[0]];
+
+        output
+    }
+}
+
+// The two stage K-weighting filter from ITU-R BS.1770
+fn k_weighting(sample_rate: f64) -> [Biquad; 2] {
+    let f0 = 1_681.974_450_955_533;
+    let gain = 3.999_843_853_973_347;
+    let q = 0.707_175_236_955_419_6;
+
+    let k = (std::f64::consts::PI * f0 / sample_rate).tan();
+    let vh = 10f64.powf(gain / 20.);
+    let vb = vh.powf(0.499_666_774_154_541_6);
+    let a0 = 1. + k / q + k * k;
+
+    let shelf = Biquad {
+        b: [
+            (vh + vb * k / q + k * k) / a0,
+            2. * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        a: [1., 2. * (k * k - 1.) / a0, (1. - k / q + k * k) / a0],
+        ..Default::default()
+    };
+
+    let f0 = 38.135_470_876_024_44;
+    let q = 0.500_327_037_323_877_3;
+
+    let k = (std::f64::consts::PI * f0 / sample_rate).tan();
+    let a0 = 1. + k / q + k * k;
+
+    let high_pass = Biquad {
+        b: [1., -2., 1.],
+        a: [1., 2. * (k * k - 1.) / a0, (1. - k / q + k * k) / a0],
+        ..Default::default()
+    };
+
+    [shelf, high_pass]
+}
+
+fn true_peak_taps() -> [[f64; TRUE_PEAK_TAPS]; TRUE_PEAK_PHASES] {
+    let len = TRUE_PEAK_TAPS * TRUE_PEAK_PHASES;
+    let center = (len - 1) as f64 / 2.;
+    let mut taps = [[0.; TRUE_PEAK_TAPS]; TRUE_PEAK_PHASES];
+
+    for n in 0..len {
+        let x = (n as f64 - center) / TRUE_PEAK_PHASES as f64;
+        let sinc = if x == 0. {
+            1.
+        } else {
+            (std::f64::consts::PI * x).sin() / (std::f64::consts::PI * x)
+        };
+        let phase = 2. * std::f64::consts::PI * n as f64 / (len - 1) as f64;
+        let window = 0.42 - 0.5 * phase.cos() + 0.08 * (2. * phase).cos();
+
+        taps[n % TRUE_PEAK_PHASES][n / TRUE_PEAK_PHASES] = sinc * window;
+    }
+
+    taps
+}
+
+struct ChannelMeter {
+    filters: [Biquad; 2],
+    history: [f64; TRUE_PEAK_TAPS],
+    history_position: usize,
+    energy: f64,
+    block_position: usize,
+    blocks: [f64; BLOCKS],
+    block_count: usize,
+    histogram: Box<[u32]>,
+}
+
+impl ChannelMeter {
+    fn new(sample_rate: f64) -> ChannelMeter {
+        ChannelMeter {
+            filters: k_weighting(sample_rate),
+            history: [0.; TRUE_PEAK_TAPS],
+            history_position: 0,
+            energy: 0.,
+            block_position: 0,
+            blocks: [0.; BLOCKS],
+            block_count: 0,
+            histogram: vec![0; HISTOGRAM_BINS].into_boxed_slice(),
+        }
+    }
+
+    fn average(&self, blocks: usize) -> f64 {
+        let blocks = blocks.min(self.block_count);
+
+        if blocks == 0 {
+            return 0.;
+        }
+
+        (0..blocks)
+            .map(|i| self.blocks[(self.block_count - 1 - i) % BLOCKS])
+            .sum::<f64>()
+            / blocks as f64
+    }
+
+    fn integrated(&self) -> f64 {
+        let bin_loudness = |bin: usize| ABSOLUTE_GATE + (bin as f64 + 0.5) / 10.;
+
+        let gated = |threshold: f64| {
+            let mut count = 0;
+            let mut energy = 0.;
+
+            for (bin, blocks) in self.histogram.iter().enumerate() {
+                if *blocks > 0 && bin_loudness(bin) >= threshold {
+                    count += *blocks;
+                    energy += *blocks as f64 * mean_square(bin_loudness(bin));
+                }
+            }
+
+            if count == 0 {
+                0.
+            } else {
+                energy / count as f64
+            }
+        };
+
+        let absolute = gated(ABSOLUTE_GATE);
+
+        if absolute > 0. {
+            loudness(gated(loudness(absolute) + RELATIVE_GATE))
+        } else {
+            f64::NEG_INFINITY
+        }
+    }
+
+    fn end_block(&mut self, block_size: usize, readings: &ChannelReadings) {
+        self.blocks[self.block_count % BLOCKS] = self.energy / block_size as f64;
+        self.block_count += 1;
+        self.energy = 0.;
+        self.block_position = 0;
+
+        let momentary = loudness(self.average(MOMENTARY_BLOCKS));
+
+        if self.block_count >= MOMENTARY_BLOCKS && momentary >= ABSOLUTE_GATE {
+            let bin = ((momentary - ABSOLUTE_GATE) * 10.) as usize;
+            self.histogram[bin.min(HISTOGRAM_BINS - 1)] += 1;
+            readings.integrated.store(self.integrated());
+        }
+
+        readings.momentary.store(momentary);
+        readings.short_term.store(loudness(self.average(BLOCKS)));
+    }
+}
+
+/// Measures a signal on the audio thread and publishes the levels to
+/// `MeterReadings`.
+pub(crate) struct Meter {
+    readings: Arc<MeterReadings>,
+    channels: Vec<ChannelMeter>,
+    taps: [[f64; TRUE_PEAK_TAPS]; TRUE_PEAK_PHASES],
+    block_size: usize,
+}
+
+impl Meter {
+    pub(crate) fn new(channels: usize, sample_rate: f64) -> Meter {
+        Meter {
+            readings: Arc::new(MeterReadings {
+                channels: (0..channels).map(|_| ChannelReadings::new()).collect(),
+            }),
+            channels: (0..channels)
+                .map(|_| ChannelMeter::new(sample_rate))
+                .collect(),
+            taps: true_peak_taps(),
+            block_size: ((sample_rate / 10.) as usize).max(1),
+        }
+    }
+
+    pub(crate) fn set_sample_rate(&mut self, sample_rate: f64) {
+        for meter in self.channels.iter_mut() {
+            meter.filters = k_weighting(sample_rate);
+            meter.energy = 0.;
+            meter.block_position = 0;
+        }
+
+        self.block_size = ((sample_rate / 10.) as usize).max(1);
+    }
+
+    pub(crate) fn readings(&self) -> Arc<MeterReadings> {
+        Arc::clone(&self.readings)
+    }
+
+    /// Measure the first `frames` frames of each buffer, scaled by `gain`
+    pub(crate) fn process<S: Sample>(
+        &mut self,
+        buffers: &[BufferPoolReference<S>],
+        frames: usize,
+        gain: f64,
+    ) {
+        if frames == 0 {
+            return;
+        }
+
+        for ((meter, readings), buffer) in self
+            .channels
+            .iter_mut()
+            .zip(self.readings.channels.iter())
+            .zip(buffers.iter())
+        {
+            let mut peak: f64 = 0.;
+            let mut true_peak: f64 = 0.;
+            let mut sum = 0.;
+
+            for sample in buffer.as_ref().iter().take(frames) {
+                let value = to_f64(*sample) * gain;
+
+                peak = peak.max(value.abs());
+                sum += value * value;
+
+                meter.history_position =
+                    (meter.history_position + TRUE_PEAK_TAPS - 1) % TRUE_PEAK_TAPS;
+                meter.history[meter.history_position] = value;
+
+                for phase in self.taps.iter() {
+                    let mut interpolated = 0.;
+
+                    for (i, tap) in phase.iter().enumerate() {
+                        interpolated +=
+                            tap * meter.history[(meter.history_position + i) % TRUE_PEAK_TAPS];
+                    }
+
+                    true_peak = true_peak.max(interpolated.abs());
+                }
+
+                let shelved = meter.filters[0].process(value);
+                let weighted = meter.filters[1].process(shelved);
+
+                meter.energy += weighted * weighted;
+                meter.block_position += 1;
+
+                if meter.block_position == self.block_size {
+                    meter.end_block(self.block_size, readings);
+                }
+            }
+
+            readings.peak.store(peak);
+            readings.true_peak.store(true_peak.max(peak));
+            readings.rms.store((sum / frames as f64).sqrt());
+        }
+    }
+}
@@ -2,17 +2,49 @@ extern crate bufferpool;
 
 mod arena;
 pub mod builder;
+mod fade;
+pub mod handle;
+pub mod history;
+pub mod meter;
 pub mod node;
+pub mod patch;
+pub mod profile;
+mod prune;
+mod query;
+mod resample;
+pub mod subgraph;
+pub mod tap;
+pub mod topology;
+pub mod voice;
 
 pub use builder::*;
+pub use handle::*;
+pub use history::*;
+pub use meter::*;
 pub use node::*;
-
-use crate::route::Route;
+pub use patch::*;
+pub use profile::*;
+pub use subgraph::*;
+pub use tap::*;
+pub use topology::*;
+pub use voice::*;
+
+use crate::convert::to_f64;
+#[cfg(feature = "rt-guard")]
+use crate::guard::{Guard, GuardMode, GuardReport};
+use crate::route::{Route, Tail};
 use generational_arena::{Arena, Index};
 use sample::Sample;
 use std::collections::HashSet;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Instant;
 
-use arena::{insert_with, split_at};
+use arena::{insert_with, split_at, ArenaSplit};
+use fade::{Fade, Side};
+use meter::Meter;
+use node::port_range;
+use resample::{domain_frames, first_frame, held_frame, Resampler};
 
 use bufferpool::{BufferPool, BufferPoolBuilder, BufferPoolReference};
 
@@ -21,9 +53,91 @@ pub struct RouteGraph<S: Sample + Default, R> {
     visited: HashSet<Index>,
     temp: Vec<BufferPoolReference<S>>,
     arena: Arena<Node<S, R>>,
+    inputs: Vec<Index>,
+    outputs: Vec<Index>,
     max_channels: usize,
     pool: BufferPool<S>,
+    clock: usize,
+    sample_rate: f64,
+    profile: Option<Arc<GraphProfile>>,
+    pending_topology: Option<Topology<S, R>>,
+    retired_topology: Option<Topology<S, R>>,
+    fading_topology: Option<Topology<S, R>>,
+    crossfade: Option<Fade>,
+    #[cfg(feature = "rt-guard")]
+    guard_mode: GuardMode,
+    #[cfg(feature = "rt-guard")]
+    guard_report: Arc<GuardReport>,
     sorted: bool,
+    pruning: bool,
+    latency: usize,
+    graph_id: usize,
+}
+
+// Sort nodes, given as the positions of the nodes they connect to, so
+// that each node comes before everything it's connected to. If there's
+// a cycle the position of a node on it is returned instead.
+pub(crate) fn sort_positions(edges: &[Vec<usize>]) -> Result<Vec<usize>, usize> {
+    // 0 is unvisited, 1 is being visited and 2 is done
+    let mut state = vec![0u8; edges.len()];
+    let mut stack: Vec<(usize, usize)> = Vec::new();
+    let mut ordering = Vec::with_capacity(edges.len());
+
+    for start in 0..edges.len() {
+        if state[start] != 0 {
+            continue;
+        }
+
+        state[start] = 1;
+        stack.push((start, 0));
+
+        while let Some((node, next)) = stack.pop() {
+            match edges[node].get(next) {
+                Some(target) => {
+                    stack.push((node, next + 1));
+
+                    match state[*target] {
+                        0 => {
+                            state[*target] = 1;
+                            stack.push((*target, 0));
+                        }
+                        1 => return Err(*target),
+                        _ => {}
+                    }
+                }
+                None => {
+                    state[node] = 2;
+                    ordering.push(node);
+                }
+            }
+        }
+    }
+
+    ordering.reverse();
+
+    Ok(ordering)
+}
+
+// Find the position of a terminal node and the first host channel it uses.
+fn terminal_channel<S, R>(
+    terminals: &[Index],
+    id: Index,
+    rest: &mut ArenaSplit<Node<S, R>>,
+) -> Option<(usize, usize)> {
+    let mut channel = 0;
+
+    for (i, terminal) in terminals.iter().enumerate() {
+        if *terminal == id {
+            return Some((i, channel));
+        }
+
+        channel += rest
+            .get_mut(*terminal)
+            .map(|node| node.output_channels)
+            .unwrap_or(0);
+    }
+
+    None
 }
 
 // Implement Send and Sync if all the routes are Send.
@@ -61,7 +175,7 @@ where
     R: Route<S, Context = C>,
 {
     fn from(arena: Arena<Node<S, R>>) -> Self {
-        Self::build(arena, 1024)
+        Self::build(arena, 1024, 44_100.)
     }
 }
 
@@ -70,15 +184,18 @@ where
     S: Sample + Default,
     R: Route<S, Context = C>,
 {
-    pub(crate) fn build(arena: Arena<Node<S, R>>, buffer_size: usize) -> Self {
+    // Routes are prepared once, at `sample_rate`
+    pub(crate) fn build(arena: Arena<Node<S, R>>, buffer_size: usize, sample_rate: f64) -> Self {
         let ordering: Vec<Index> = Vec::with_capacity(arena.len());
 
         let capacity = arena.len();
-        let max_channels = arena.iter().fold(0, |a, (_, b)| a.max(b.channels));
+        let max_channels = arena.iter().fold(0, |a, (_, b)| a.max(b.output_channels));
 
         let mut graph = Self {
             ordering,
             arena,
+            inputs: vec![],
+            outputs: vec![],
             visited: HashSet::with_capacity(capacity),
             temp: Vec::with_capacity(max_channels),
             max_channels,
@@ -86,9 +203,24 @@ where
                 .with_capacity(0)
                 .with_buffer_size(0)
                 .build(),
+            clock: 0,
+            sample_rate,
+            profile: None,
+            pending_topology: None,
+            retired_topology: None,
+            fading_topology: None,
+            crossfade: None,
+            #[cfg(feature = "rt-guard")]
+            guard_mode: GuardMode::Log,
+            #[cfg(feature = "rt-guard")]
+            guard_report: Arc::new(GuardReport::default()),
             sorted: false,
+            pruning: false,
+            latency: 0,
+            graph_id: handle::next_graph_id(),
         };
 
+        graph.rebuild_incoming();
         graph.topographic_sort();
 
         let buffer_count = graph.count_required_temp_buffers();
@@ -98,10 +230,72 @@ where
             .with_buffer_size(buffer_size)
             .build();
 
+        graph.prepare_all();
+
         graph
     }
 
-    fn process_parts<I: Iterator<Item = usize>>(&mut self, ranges: I, context: &mut C) {
+    fn fill_input_nodes<I: AsRef<[S]>>(
+        arena: &mut Arena<Node<S, R>>,
+        pool: &mut BufferPool<S>,
+        input_nodes: &[Index],
+        inputs: &[&[I]],
+        crossfade: Option<(&Fade, Side)>,
+        clock: usize,
+        part: Range<usize>,
+    ) {
+        let mut channel = 0;
+
+        for id in input_nodes {
+            if let Some(node) = arena.get_mut(*id) {
+                while node.buffers.len() < node.input_channels {
+                    node.buffers.push(pool.get_cleared_space().unwrap());
+                }
+
+                // Nodes in slower rate domains only read the frames they process
+                let divisor = node.rate_divisor;
+                let first = first_frame(divisor, clock);
+
+                let inputs = inputs.iter().flat_map(|inputs| inputs.iter()).skip(channel);
+
+                for (buffer, input) in node.buffers.iter_mut().zip(inputs) {
+                    if let Some(input) = input.as_ref().get(part.start..) {
+                        let input = input
+                            .iter()
+                            .take(part.len())
+                            .enumerate()
+                            .skip(first)
+                            .step_by(divisor);
+
+                        for (output, (i, input)) in buffer.as_mut().iter_mut().zip(input) {
+                            let input = match crossfade {
+                                Some((crossfade, side)) => {
+                                    crossfade.apply_side(side, *input, part.start + i)
+                                }
+                                None => *input,
+                            };
+
+                            *output = output.add_amp(input.to_signed_sample());
+                        }
+                    }
+                }
+
+                channel += node.input_channels;
+            }
+        }
+    }
+
+    fn process_parts<T, I, O>(
+        &mut self,
+        ranges: T,
+        inputs: &[&[I]],
+        outputs: &mut [O],
+        context: &mut C,
+    ) where
+        T: Iterator<Item = usize>,
+        I: AsRef<[S]>,
+        O: AsMut<[S]>,
+    {
         let temp = &mut self.temp;
         let arena = &mut self.arena;
 
@@ -109,35 +303,262 @@ where
 
         let ordering = &self.ordering;
 
+        let input_nodes = &self.inputs;
+        let output_nodes = &self.outputs;
+
+        let clock = &mut self.clock;
+
+        let crossfade = self.crossfade.as_ref();
+
+        // The old topology's terminals are faded out while a new one is
+        // crossfaded in
+        let fading = self.fading_topology.as_ref();
+        let previous_inputs = fading.map_or(&[][..], |fading| &fading.previous_inputs);
+        let previous_outputs = fading.map_or(&[][..], |fading| &fading.previous_outputs);
+        let output_sides = fading.map_or(&[][..], |fading| &fading.output_sides);
+        let previous_output_sides = fading.map_or(&[][..], |fading| &fading.previous_output_sides);
+
+        #[cfg(feature = "rt-guard")]
+        let guard_mode = self.guard_mode;
+
+        #[cfg(feature = "rt-guard")]
+        let guard_report = &self.guard_report;
+
+        let mut offset = 0;
+
         for frames in ranges {
+            if inputs.iter().any(|inputs| !inputs.is_empty()) {
+                let part = offset..offset + frames;
+
+                for (terminals, side) in
+                    [(&input_nodes[..], Side::New), (previous_inputs, Side::Old)].iter()
+                {
+                    let crossfade = crossfade.map(|crossfade| (crossfade, *side));
+                    Self::fill_input_nodes(
+                        arena,
+                        pool,
+                        terminals,
+                        inputs,
+                        crossfade,
+                        *clock,
+                        part.clone(),
+                    );
+                }
+            }
+
             for id in ordering {
                 if let Some((current, mut rest)) = split_at(arena, *id) {
+                    let node_frames = domain_frames(current.rate_divisor, *clock, frames);
+
+                    // Nodes that have faded out are waiting to be collected,
+                    // and pruned nodes have nowhere for their signal to go
+                    if current.is_faded_out() || current.pruned {
+                        current.buffers.drain(..).for_each(drop);
+                        continue;
+                    }
+
+                    if let Tail::Frames(tail) = current.route.tail() {
+                        let silent = current.buffers.iter().all(|buffer| {
+                            buffer
+                                .as_ref()
+                                .iter()
+                                .take(node_frames)
+                                .all(|sample| *sample == S::equilibrium())
+                        });
+
+                        if silent {
+                            current.asleep = current.silent_frames >= tail;
+                            current.silent_frames =
+                                current.silent_frames.saturating_add(node_frames);
+                        } else {
+                            current.asleep = false;
+                            current.silent_frames = 0;
+                        }
+
+                        if current.asleep {
+                            current.buffers.drain(..).for_each(drop);
+                            continue;
+                        }
+                    }
+
+                    let sidechain_channels = current.sidechain_channels();
                     let buffers = &current.buffers;
                     let node_route = &mut current.route;
-                    let connections = &current.connections;
+                    let connections = &mut current.connections;
+                    let fading_connections = &mut current.fading_connections;
+                    let fade = current.fade.as_ref();
+                    let divisor = current.rate_divisor;
+                    let output = &mut temp[..current.output_channels];
+
+                    // The output buffers are shared by every node
+                    if !node_route.overwrites_output() {
+                        for buffer in output.iter_mut() {
+                            for sample in buffer.as_mut().iter_mut() {
+                                *sample = S::equilibrium();
+                            }
+                        }
+                    }
+
+                    let started = current.profile.as_ref().map(|_| Instant::now());
+
+                    #[cfg(feature = "rt-guard")]
+                    let guard = Guard::enter(Some(*id), guard_mode);
+
+                    // Like the other inputs, the sidechain is empty if nothing
+                    // has been sent to it
+                    if sidechain_channels > 0 {
+                        let (input, sidechain) =
+                            buffers.split_at(current.input_channels.min(buffers.len()));
+                        node_route.process_sidechain(
+                            input,
+                            sidechain,
+                            output,
+                            node_frames,
+                            context,
+                        );
+                    } else {
+                        node_route.process(buffers, output, node_frames, context);
+                    }
+
+                    #[cfg(feature = "rt-guard")]
+                    guard.finish(guard_report);
 
-                    node_route.process(buffers, temp, frames, context);
+                    if let Some(started) = started {
+                        let elapsed = current.elapsed.unwrap_or_default();
+                        current.elapsed = Some(elapsed + started.elapsed());
+                    }
+
+                    if let Some(meter) = &mut current.meter {
+                        meter.process(output, node_frames, 1.);
+                    }
+
+                    if let Some(tap) = &mut current.tap {
+                        tap.write(output, node_frames);
+                    }
+
+                    if !outputs.is_empty() {
+                        let terminals = [
+                            (&output_nodes[..], output_sides),
+                            (previous_outputs, previous_output_sides),
+                        ];
+
+                        for (terminals, sides) in terminals.iter() {
+                            let (position, channel) =
+                                match terminal_channel(terminals, *id, &mut rest) {
+                                    Some(terminal) => terminal,
+                                    None => continue,
+                                };
+
+                            let side = sides.get(position).copied().unwrap_or(Side::Both);
 
-                    for send in connections {
+                            for (host, buffer) in
+                                outputs.iter_mut().skip(channel).zip(output.iter())
+                            {
+                                let buffer = buffer.as_ref();
+
+                                if let Some(host) = host.as_mut().get_mut(offset..) {
+                                    for (i, host) in host.iter_mut().take(frames).enumerate() {
+                                        // Slower nodes hold each frame until their next one
+                                        let input = buffer[held_frame(divisor, *clock, i)];
+
+                                        let sample = match crossfade {
+                                            Some(crossfade) => {
+                                                crossfade.apply_side(side, input, offset + i)
+                                            }
+                                            None => input,
+                                        };
+
+                                        let sample = match fade {
+                                            Some(fade) => fade.apply(sample, offset + i),
+                                            None => sample,
+                                        };
+
+                                        *host = host.add_amp(sample.to_signed_sample());
+                                    }
+                                }
+                            }
+                        }
+                    }
+
+                    for send in connections.iter_mut().chain(fading_connections.iter_mut()) {
                         if let Some(out_route) = rest.get_mut(send.id) {
-                            if out_route.buffers.len() < out_route.channels {
-                                for _ in 0..(out_route.channels - out_route.buffers.len()) {
+                            let ranges = (
+                                port_range(&current.output_ports, send.source_port),
+                                port_range(&out_route.input_ports, send.target_port),
+                            );
+
+                            let (source, target, channels) = match ranges {
+                                (Some((source, from)), Some((target, to))) => {
+                                    (source, target, from.min(to))
+                                }
+                                _ => continue,
+                            };
+
+                            let needed = out_route.input_buffers();
+
+                            if out_route.buffers.len() < needed {
+                                for _ in 0..(needed - out_route.buffers.len()) {
                                     out_route.buffers.push(pool.get_cleared_space().unwrap());
                                 }
                             }
 
+                            let (amount, side) = (send.amount, send.side);
+
+                            // The amount `frame` frames into the block, faded
+                            // in or out along with the node or topology
+                            let gain = |frame: usize| {
+                                let amount = match fade {
+                                    Some(fade) => fade.apply(amount, frame),
+                                    None => amount,
+                                };
+
+                                match crossfade {
+                                    Some(crossfade) => crossfade.apply_side(side, amount, frame),
+                                    None => amount,
+                                }
+                            };
+
+                            // Resampled connections and meters use the gain
+                            // from the middle of the block
+                            let amount = gain(offset + frames / 2);
+
+                            let inputs = &output[source..source + channels];
+                            let outputs = &mut out_route.buffers[target..target + channels];
+
+                            if let Some(meter) = &mut send.meter {
+                                meter.process(inputs, node_frames, to_f64(amount));
+                            }
+
+                            if let Some(resampler) = &mut send.resampler {
+                                for (channel, (output_vector, input_vector)) in
+                                    outputs.iter_mut().zip(inputs.iter()).enumerate()
+                                {
+                                    resampler.process(
+                                        channel,
+                                        *clock,
+                                        frames,
+                                        &input_vector.as_ref()[..node_frames],
+                                        output_vector.as_mut(),
+                                        amount,
+                                    );
+                                }
+
+                                continue;
+                            }
+
                             for (output_vector, input_vector) in
-                                out_route.buffers.iter_mut().zip(temp.iter())
+                                outputs.iter_mut().zip(inputs.iter())
                             {
-                                for (output, input) in output_vector
+                                for (i, (output, input)) in output_vector
                                     .as_mut()
                                     .iter_mut()
                                     .zip(input_vector.as_ref().iter())
+                                    .enumerate()
                                 {
+                                    let amount = gain(offset + i * divisor);
+
                                     *output = output.add_amp(
-                                        input
-                                            .mul_amp(send.amount.to_float_sample())
-                                            .to_signed_sample(),
+                                        input.mul_amp(amount.to_float_sample()).to_signed_sample(),
                                     );
                                 }
                             }
@@ -147,12 +568,57 @@ where
                     current.buffers.drain(..).for_each(drop);
                 }
             }
+
+            offset += frames;
+            *clock = clock.wrapping_add(frames);
         }
     }
 
-    pub fn process(&mut self, frames: usize, context: &mut C) {
+    /// Process the graph, copying `inputs` into the graph's input nodes and
+    /// the output of the graph's output nodes into `outputs`.
+    pub(crate) fn process_with_buffers<I, O>(
+        &mut self,
+        inputs: &[I],
+        outputs: &mut [O],
+        frames: usize,
+        context: &mut C,
+    ) where
+        I: AsRef<[S]>,
+        O: AsMut<[S]>,
+    {
+        self.process_with_sidechain(inputs, &[], outputs, frames, context);
+    }
+
+    /// Like `process_with_buffers`, with the `sidechain` channels
+    /// following on from `inputs` into the input nodes
+    pub(crate) fn process_with_sidechain<I, O>(
+        &mut self,
+        inputs: &[I],
+        sidechain: &[I],
+        outputs: &mut [O],
+        frames: usize,
+        context: &mut C,
+    ) where
+        I: AsRef<[S]>,
+        O: AsMut<[S]>,
+    {
+        let inputs = [inputs, sidechain];
+
+        self.advance_topology();
+
         let buffer_size = self.buffer_size();
 
+        let started = self.profile.as_ref().map(|_| Instant::now());
+
+        #[cfg(feature = "rt-guard")]
+        let guard = Guard::enter(None, self.guard_mode);
+
+        for output in outputs.iter_mut() {
+            for sample in output.as_mut().iter_mut().take(frames) {
+                *sample = S::equilibrium();
+            }
+        }
+
         {
             let temp = &mut self.temp;
             let pool = &mut self.pool;
@@ -164,14 +630,169 @@ where
 
         if buffer_size >= frames {
             let range = (0..1).map(|_| frames);
-            self.process_parts(range, context)
+            self.process_parts(range, &inputs, outputs, context)
         } else {
-            let range = (0..=((frames + buffer_size - 1) / buffer_size))
-                .map(|i| (frames - ((i.max(1) - 1) * buffer_size)).min(buffer_size));
-            self.process_parts(range, context)
+            let range = (0..(frames + buffer_size - 1) / buffer_size)
+                .map(|i| (frames - i * buffer_size).min(buffer_size));
+            self.process_parts(range, &inputs, outputs, context)
         }
 
         self.temp.drain(..).for_each(drop);
+
+        if let Some(crossfade) = &mut self.crossfade {
+            crossfade.advance(frames);
+        }
+
+        for (_, node) in self.arena.iter_mut() {
+            // Nodes are timed once per block, however many parts it's split into
+            if let (Some(profile), Some(elapsed)) = (&node.profile, node.elapsed.take()) {
+                profile.record(elapsed);
+            }
+
+            let faded_in = match &mut node.fade {
+                Some(fade) => {
+                    fade.advance(frames);
+                    fade.is_fading_in() && fade.is_finished()
+                }
+                None => false,
+            };
+
+            if faded_in {
+                node.fade = None;
+            }
+        }
+
+        #[cfg(feature = "rt-guard")]
+        guard.finish(&self.guard_report);
+
+        if let (Some(profile), Some(started)) = (&self.profile, started) {
+            profile.record(started.elapsed(), frames);
+        }
+    }
+
+    pub fn process(&mut self, frames: usize, context: &mut C) {
+        self.process_with_buffers::<&[S], &mut [S]>(&[], &mut [], frames, context);
+    }
+
+    /// Process the graph using audio from the host. Each slice in `inputs` is
+    /// a channel that is mixed into the input nodes, and each slice in `outputs`
+    /// is a channel that is filled from the output nodes. Channels are assigned
+    /// to the nodes in the order they were set.
+    ///
+    /// The number of frames processed is the length of the shortest slice.
+    pub fn process_with_io(&mut self, inputs: &[&[S]], outputs: &mut [&mut [S]], context: &mut C) {
+        let frames = inputs
+            .iter()
+            .map(|input| input.len())
+            .chain(outputs.iter().map(|output| output.len()))
+            .min()
+            .unwrap_or(0);
+
+        self.process_with_buffers(inputs, outputs, frames, context);
+    }
+
+    fn terminal_channels(&self, terminals: &[Index]) -> usize {
+        terminals
+            .iter()
+            .filter_map(|id| self.arena.get(*id).map(|node| node.input_channels))
+            .sum()
+    }
+
+    /// Set the nodes that receive the input passed to `process_with_io`
+    pub fn set_inputs(&mut self, inputs: &[Index]) {
+        let current = self.terminal_channels(&self.inputs);
+        let channels = self.terminal_channels(inputs);
+
+        // Input nodes hold on to their buffers until they're processed,
+        // so make sure there's enough space for them.
+        if channels > current {
+            self.pool.reserve(channels - current);
+        }
+
+        self.inputs.clear();
+        self.inputs.extend_from_slice(inputs);
+        self.update_pruning();
+        self.update_latency();
+    }
+
+    /// Set the nodes whose output is copied to the output passed to `process_with_io`
+    pub fn set_outputs(&mut self, outputs: &[Index]) {
+        self.outputs.clear();
+        self.outputs.extend_from_slice(outputs);
+        self.update_pruning();
+        self.update_latency();
+    }
+
+    pub fn inputs(&self) -> &[Index] {
+        &self.inputs
+    }
+
+    pub fn outputs(&self) -> &[Index] {
+        &self.outputs
+    }
+
+    /// The largest latency, in frames, along any path from the input nodes
+    /// to the output nodes. If no terminals are set, every node is considered.
+    ///
+    /// This is worked out when the graph is sorted or its terminals change,
+    /// so it's cheap to call while processing.
+    pub fn latency(&self) -> usize {
+        self.latency
+    }
+
+    // Work out the graph's latency by following the ordering, without
+    // allocating
+    pub(crate) fn update_latency(&mut self) {
+        let arena = &mut self.arena;
+        let inputs = &self.inputs;
+        let outputs = &self.outputs;
+
+        for (_, node) in arena.iter_mut() {
+            node.input_latency = None;
+        }
+
+        let mut latency = 0;
+
+        for id in self.ordering.iter() {
+            let (current, mut rest) = match split_at(arena, *id) {
+                Some(split) => split,
+                None => continue,
+            };
+
+            let start = if inputs.is_empty() || inputs.contains(id) {
+                Some(0)
+            } else {
+                None
+            };
+
+            if let Some(input_latency) = current.input_latency.or(start) {
+                let output_latency = input_latency + current.route.latency() * current.rate_divisor;
+
+                if outputs.is_empty() || outputs.contains(id) {
+                    latency = latency.max(output_latency);
+                }
+
+                for connection in current.connections.iter() {
+                    let resampler_latency = connection
+                        .resampler
+                        .as_ref()
+                        .map(|resampler| resampler.latency())
+                        .unwrap_or(0);
+
+                    if let Some(target) = rest.get_mut(connection.id) {
+                        let arriving = output_latency + resampler_latency;
+
+                        target.input_latency = Some(
+                            target
+                                .input_latency
+                                .map_or(arriving, |latency| latency.max(arriving)),
+                        );
+                    }
+                }
+            }
+        }
+
+        self.latency = latency;
     }
 
     /// Change the graph buffer size
@@ -180,6 +801,67 @@ where
     /// If any of the internal buffers have been borrowed
     pub fn set_buffer_size(&mut self, buffer: usize) {
         self.pool.change_buffer_size(buffer);
+        self.prepare_all();
+    }
+
+    fn prepare_node(sample_rate: f64, buffer_size: usize, node: &mut Node<S, R>) {
+        let divisor = node.rate_divisor;
+
+        node.route.prepare(
+            sample_rate / divisor as f64,
+            domain_frames(divisor, 0, buffer_size),
+            node.output_channels,
+        );
+    }
+
+    pub(crate) fn prepare_all(&mut self) {
+        let sample_rate = self.sample_rate;
+        let buffer_size = self.buffer_size();
+
+        for (_, node) in self.arena.iter_mut() {
+            Self::prepare_node(sample_rate, buffer_size, node);
+        }
+
+        // Routes can change their latency when they're prepared
+        self.update_latency();
+    }
+
+    /// Change the sample rate, preparing every route again. This shouldn't
+    /// be called on the audio thread since routes may allocate.
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+
+        if let Some(profile) = &self.profile {
+            profile.set_sample_rate(sample_rate);
+        }
+
+        for (_, node) in self.arena.iter_mut() {
+            let node_rate = sample_rate / node.rate_divisor as f64;
+
+            if let Some(meter) = &mut node.meter {
+                meter.set_sample_rate(node_rate);
+            }
+
+            let connections = node.connections.iter_mut();
+
+            for connection in connections.chain(node.fading_connections.iter_mut()) {
+                if let Some(meter) = &mut connection.meter {
+                    meter.set_sample_rate(node_rate);
+                }
+            }
+        }
+
+        self.prepare_all();
+    }
+
+    pub fn sample_rate(&self) -> f64 {
+        self.sample_rate
+    }
+
+    pub(crate) fn release_all(&mut self) {
+        for (_, node) in self.arena.iter_mut() {
+            node.route.release();
+        }
     }
 
     pub fn buffer_size(&self) -> usize {
@@ -197,21 +879,37 @@ where
             visited: HashSet::new(),
             temp: vec![],
             arena: Arena::new(),
+            inputs: vec![],
+            outputs: vec![],
             pool: BufferPool::default(),
+            clock: 0,
+            sample_rate: 44_100.,
+            profile: None,
+            pending_topology: None,
+            retired_topology: None,
+            fading_topology: None,
+            crossfade: None,
+            #[cfg(feature = "rt-guard")]
+            guard_mode: GuardMode::Log,
+            #[cfg(feature = "rt-guard")]
+            guard_report: Arc::new(GuardReport::default()),
 
             max_channels: 0,
             sorted: true,
+            pruning: false,
+            latency: 0,
+            graph_id: handle::next_graph_id(),
         }
     }
 
     fn count_buffers_for_node(&self, node: &Node<S, R>) -> usize {
         let connections = &node.connections;
 
-        let mut count = node.channels;
+        let mut count = node.input_buffers();
 
         for send in connections {
             if let Some(out_route) = self.arena.get(send.id) {
-                count += out_route.channels;
+                count += out_route.input_buffers();
             }
         }
 
@@ -229,7 +927,7 @@ where
         {
             count += self.count_buffers_for_node(node);
             max = max.max(count);
-            count -= node.channels.min(count);
+            count -= node.input_buffers().min(count);
         }
 
         max
@@ -262,18 +960,29 @@ where
         let ordering = &mut (self.ordering);
         ordering.truncate(0);
 
-        for (_, node) in self.arena.iter() {
-            Self::topographic_sort_inner(visited, ordering, &self.arena, node);
+        for (id, node) in self.arena.iter() {
+            if !visited.contains(&id) {
+                Self::topographic_sort_inner(visited, ordering, &self.arena, node);
+            }
         }
 
         ordering.reverse();
         assert_eq!(ordering.len(), self.arena.len());
 
+        self.update_resamplers();
+
         self.sorted = true;
+        self.update_pruning();
+        self.update_latency();
     }
 
+    /// Silence the buffers and reset every route
     pub fn silence_all_buffers(&mut self) {
         self.pool.clear();
+
+        for (_, node) in self.arena.iter_mut() {
+            node.route.reset();
+        }
     }
 
     pub fn len(&self) -> usize {
@@ -281,20 +990,88 @@ where
     }
 
     // Set the volume / amount of a particular route
+    #[deprecated(note = "Use `connect` with node handles instead")]
     pub fn set_route_amount(&mut self, source: Index, target: Index, amount: S) {
-        self.with_node_connections(source, |connections| {
-            if let Some(position) = connections.iter().position(|c| &c.id == &target) {
-                if amount == S::equilibrium() {
-                    connections.swap_remove(position);
-                } else {
-                    connections.get_mut(position).unwrap().amount = amount;
-                }
+        self.set_connection_amount(source, 0, target, 0, amount);
+    }
+
+    /// Set the amount sent from an output port of `source` to an input port
+    /// of `target`. Setting it to equilibrium removes the connection.
+    #[deprecated(note = "Use `connect` with port handles instead")]
+    pub fn set_port_amount(
+        &mut self,
+        source: Index,
+        source_port: usize,
+        target: Index,
+        target_port: usize,
+        amount: S,
+    ) {
+        self.set_connection_amount(source, source_port, target, target_port, amount);
+    }
+
+    // Set the amount sent from an output port of `source` to an input port
+    // of `target`. Setting it to equilibrium removes the connection.
+    pub(crate) fn set_connection_amount(
+        &mut self,
+        source: Index,
+        source_port: usize,
+        target: Index,
+        target_port: usize,
+        amount: S,
+    ) {
+        let ports = (
+            self.with_node(source, |node| node.output_ports.len()),
+            self.with_node(target, |node| node.input_ports.len()),
+        );
+
+        match ports {
+            (Some(outputs), Some(inputs)) if source_port < outputs && target_port < inputs => {}
+            _ => return,
+        }
+
+        let connections = &mut self.arena[source].connections;
+
+        let change = if let Some(position) = connections
+            .iter()
+            .position(|c| c.is_between(target, source_port, target_port))
+        {
+            if amount == S::equilibrium() {
+                connections.swap_remove(position);
+                Some(false)
             } else {
-                if amount != S::equilibrium() {
-                    connections.push(Connection::new(target, amount))
+                connections.get_mut(position).unwrap().amount = amount;
+                None
+            }
+        } else if amount != S::equilibrium() {
+            connections.push(Connection::new(target, amount).with_ports(source_port, target_port));
+            Some(true)
+        } else {
+            None
+        };
+
+        let incoming = &mut self.arena[target].incoming;
+
+        match change {
+            Some(true) => incoming.push(source),
+            Some(false) => {
+                if let Some(position) = incoming.iter().position(|id| *id == source) {
+                    incoming.swap_remove(position);
                 }
             }
-        });
+            None => {}
+        }
+
+        self.update_resamplers();
+        self.update_pruning();
+        self.update_latency();
+    }
+
+    /// Find the first node with the given label
+    pub fn node_by_label(&self, label: &str) -> Option<Index> {
+        self.arena
+            .iter()
+            .find(|(_, node)| node.label() == Some(label))
+            .map(|(id, _)| id)
     }
 
     pub fn with_node_mut<T, F: FnOnce(&mut Node<S, R>) -> T>(
@@ -314,39 +1091,192 @@ where
         id: Index,
         func: F,
     ) -> Option<T> {
-        self.with_node_mut(id, |node| func(&mut node.connections))
-    }
+        let (node, mut rest) = split_at(&mut self.arena, id)?;
 
-    pub fn remove_node(&mut self, id: Index) -> Option<Node<S, R>> {
-        let node = self.arena.remove(id);
+        let mut removed: Vec<Index> = node.connections.iter().map(|c| c.id).collect();
+        let result = func(&mut node.connections);
 
-        for (_, node) in self.arena.iter_mut() {
-            node.connections.retain(|connection| &connection.id != &id);
+        // Only the targets that were connected or disconnected need updating
+        for target in node.connections.iter().map(|c| c.id) {
+            if let Some(position) = removed.iter().position(|id| *id == target) {
+                removed.swap_remove(position);
+            } else if let Some(target) = rest.get_mut(target) {
+                target.incoming.push(id);
+            }
         }
 
-        self.sorted = false;
+        for target in removed {
+            if let Some(target) = rest.get_mut(target) {
+                if let Some(position) = target.incoming.iter().position(|source| *source == id) {
+                    target.incoming.swap_remove(position);
+                }
+            }
+        }
 
-        node
+        self.update_resamplers();
+        self.update_pruning();
+        self.update_latency();
+
+        Some(result)
     }
 
-    pub fn add_node_with_idx<F: Send + FnMut(Index) -> Node<S, R>>(
-        &mut self,
-        mut func: F,
-    ) -> Index {
-        let id = insert_with(&mut self.arena, |id| func(id));
+    // Work out the sources of every node's connections from scratch
+    fn rebuild_incoming(&mut self) {
+        let edges: Vec<(Index, Index)> = self
+            .arena
+            .iter()
+            .flat_map(|(id, node)| node.connections.iter().map(move |c| (id, c.id)))
+            .collect();
 
-        self.pool.reserve(1);
-        self.visited.reserve(1);
+        for (_, node) in self.arena.iter_mut() {
+            node.incoming.clear();
+        }
 
-        let (buffers, max_channels) = self
-            .with_node(id, |node| {
-                (self.count_buffers_for_node(node), node.channels)
-            })
-            .unwrap();
+        for (source, target) in edges {
+            if let Some(node) = self.arena.get_mut(target) {
+                node.incoming.push(source);
+            }
+        }
+    }
 
-        self.max_channels = self.max_channels.max(max_channels);
+    /// The source of each connection to a node
+    pub fn inputs_of(&self, id: Index) -> impl Iterator<Item = Index> + '_ {
+        self.arena
+            .get(id)
+            .into_iter()
+            .flat_map(|node| node.incoming.iter().copied())
+    }
 
-        let temp_capacity = self.temp.capacity();
+    /// The target of each connection from a node
+    pub fn outputs_of(&self, id: Index) -> impl Iterator<Item = Index> + '_ {
+        self.arena
+            .get(id)
+            .into_iter()
+            .flat_map(|node| node.connections.iter().map(|connection| connection.id))
+    }
+
+    pub fn remove_node(&mut self, id: Index) -> Option<Node<S, R>> {
+        let mut node = self.arena.remove(id);
+
+        if let Some(node) = &mut node {
+            node.route.release();
+
+            for source in node.incoming.iter() {
+                if let Some(source) = self.arena.get_mut(*source) {
+                    source.connections.retain(|connection| connection.id != id);
+                }
+            }
+
+            for connection in node.connections.iter() {
+                if let Some(target) = self.arena.get_mut(connection.id) {
+                    target.incoming.retain(|source| *source != id);
+                }
+            }
+        }
+
+        self.inputs.retain(|input| input != &id);
+        self.outputs.retain(|output| output != &id);
+
+        self.sorted = false;
+        self.update_pruning();
+        self.update_latency();
+
+        node
+    }
+
+    /// Wake a node that's asleep, so it processes the next block even if
+    /// its input is silent. Useful when a route receives an event.
+    pub fn wake_node(&mut self, id: Index) {
+        if let Some(node) = self.arena.get_mut(id) {
+            node.asleep = false;
+            node.silent_frames = 0;
+        }
+    }
+
+    /// Fade out the connections from a node over `frames` frames. Once the
+    /// fade is finished the node stops processing, and can be removed with
+    /// `collect_faded` so it isn't dropped on the audio thread.
+    pub fn remove_node_faded(&mut self, id: Index, frames: usize) {
+        if let Some(node) = self.arena.get_mut(id) {
+            node.fade = Some(Fade::new(frames, false));
+        }
+    }
+
+    /// Remove the nodes that have finished fading out
+    pub fn collect_faded(&mut self) -> Vec<Node<S, R>> {
+        let faded: Vec<Index> = self
+            .arena
+            .iter()
+            .filter(|(_, node)| node.is_faded_out())
+            .map(|(id, _)| id)
+            .collect();
+
+        faded
+            .into_iter()
+            .filter_map(|id| self.remove_node(id))
+            .collect()
+    }
+
+    /// Add a node, fading in its connections over `frames` frames
+    pub fn add_node_faded<F: Send + FnMut(Index) -> Node<S, R>>(
+        &mut self,
+        frames: usize,
+        func: F,
+    ) -> Index {
+        let id = self.add_node_with_idx(func);
+
+        if let Some(node) = self.arena.get_mut(id) {
+            node.fade = Some(Fade::new(frames, true));
+        }
+
+        id
+    }
+
+    pub fn add_node_with_idx<F: Send + FnMut(Index) -> Node<S, R>>(
+        &mut self,
+        mut func: F,
+    ) -> Index {
+        let id = insert_with(&mut self.arena, |id| func(id));
+        self.finish_adding_node(id);
+        id
+    }
+
+    // Connect, prepare and make space for a node that's just been inserted
+    pub(crate) fn finish_adding_node(&mut self, id: Index) {
+        if let Some((current, mut rest)) = split_at(&mut self.arena, id) {
+            for connection in current.connections.iter() {
+                if let Some(target) = rest.get_mut(connection.id) {
+                    target.incoming.push(id);
+                }
+            }
+        }
+
+        let sample_rate = self.sample_rate;
+        let buffer_size = self.buffer_size();
+
+        if let Some(node) = self.arena.get_mut(id) {
+            Self::prepare_node(sample_rate, buffer_size, node);
+        }
+
+        if let Some(profile) = &self.profile {
+            let history = profile.history();
+            self.with_node_mut(id, |node| {
+                node.profile = Some(Arc::new(ProcessingTime::new(history)))
+            });
+        }
+
+        self.pool.reserve(1);
+        self.visited.reserve(1);
+
+        let (buffers, max_channels) = self
+            .with_node(id, |node| {
+                (self.count_buffers_for_node(node), node.output_channels)
+            })
+            .unwrap();
+
+        self.max_channels = self.max_channels.max(max_channels);
+
+        let temp_capacity = self.temp.capacity();
 
         self.temp
             .reserve(temp_capacity.max(self.max_channels) - temp_capacity);
@@ -357,10 +1287,203 @@ where
             .reserve((buffers + self.max_channels).max(pool_capacity) - pool_capacity);
 
         self.sorted = false;
+        self.update_pruning();
 
         self.ordering.push(id);
 
-        id
+        self.update_resamplers();
+    }
+
+    /// Start recording how long the graph and each of its nodes take to
+    /// process, keeping the times of the last `history` blocks. The returned
+    /// profile can be read from another thread while the graph is processing.
+    pub fn enable_profiling(&mut self, history: usize) -> Arc<GraphProfile> {
+        let profile = Arc::new(GraphProfile::new(self.sample_rate, history));
+
+        for (_, node) in self.arena.iter_mut() {
+            node.profile = Some(Arc::new(ProcessingTime::new(history)));
+        }
+
+        self.profile = Some(Arc::clone(&profile));
+
+        profile
+    }
+
+    pub fn disable_profiling(&mut self) {
+        for (_, node) in self.arena.iter_mut() {
+            node.profile = None;
+        }
+
+        self.profile = None;
+    }
+
+    pub fn profile(&self) -> Option<Arc<GraphProfile>> {
+        self.profile.as_ref().map(Arc::clone)
+    }
+
+    /// The processing time of a node, if profiling is enabled
+    pub fn node_profile(&self, id: Index) -> Option<Arc<ProcessingTime>> {
+        self.arena
+            .get(id)
+            .and_then(|node| node.profile.as_ref().map(Arc::clone))
+    }
+
+    /// Measure the output of a node. The readings are updated every block
+    /// and can be read from another thread while the graph is processing.
+    pub fn attach_meter(&mut self, id: Index) -> Option<Arc<MeterReadings>> {
+        let sample_rate = self.sample_rate;
+        let node = self.arena.get_mut(id)?;
+        let meter = Meter::new(node.output_channels, sample_rate / node.rate_divisor as f64);
+        let readings = meter.readings();
+
+        node.meter = Some(meter);
+
+        Some(readings)
+    }
+
+    pub fn detach_meter(&mut self, id: Index) {
+        if let Some(node) = self.arena.get_mut(id) {
+            node.meter = None;
+        }
+    }
+
+    /// Measure the signal sent from `source` to `target`, after the
+    /// connection's amount has been applied.
+    ///
+    /// # Panics
+    /// If either port belongs to a different graph
+    pub fn attach_connection_meter(
+        &mut self,
+        source: OutputPort<Self>,
+        target: InputPort<Self>,
+    ) -> Option<Arc<MeterReadings>> {
+        let (source_port, target_port) = (source.port(), target.port());
+        let (source, target) = (self.resolve(source.node()), self.resolve(target.node()));
+
+        let (_, targets) = port_range(&self.arena.get(target)?.input_ports, target_port)?;
+        let sample_rate = self.sample_rate;
+        let node = self.arena.get_mut(source)?;
+        let (_, sources) = port_range(&node.output_ports, source_port)?;
+        let sample_rate = sample_rate / node.rate_divisor as f64;
+
+        let connection = node
+            .connections
+            .iter_mut()
+            .find(|c| c.is_between(target, source_port, target_port))?;
+
+        // Only the channels both ports have are sent
+        let meter = Meter::new(sources.min(targets), sample_rate);
+        let readings = meter.readings();
+
+        connection.meter = Some(meter);
+
+        Some(readings)
+    }
+
+    /// # Panics
+    /// If either port belongs to a different graph
+    pub fn detach_connection_meter(&mut self, source: OutputPort<Self>, target: InputPort<Self>) {
+        let (source_port, target_port) = (source.port(), target.port());
+        let (source, target) = (self.resolve(source.node()), self.resolve(target.node()));
+
+        if let Some(node) = self.arena.get_mut(source) {
+            let connections = node.connections.iter_mut();
+
+            for connection in connections.filter(|c| c.is_between(target, source_port, target_port))
+            {
+                connection.meter = None;
+            }
+        }
+    }
+
+    /// Copy the output of a node into a ring buffer that holds `capacity`
+    /// frames, which can be read from another thread.
+    pub fn attach_tap(&mut self, id: Index, capacity: usize) -> Option<Tap<S>> {
+        let node = self.arena.get_mut(id)?;
+        let (tap, writer) = Tap::new(node.output_channels, capacity);
+
+        node.tap = Some(writer);
+
+        Some(tap)
+    }
+
+    pub fn detach_tap(&mut self, id: Index) {
+        if let Some(node) = self.arena.get_mut(id) {
+            node.tap = None;
+        }
+    }
+
+    /// Choose what happens when a route allocates while processing. Only
+    /// allocations made through `GuardedAllocator` are detected.
+    #[cfg(feature = "rt-guard")]
+    pub fn set_guard_mode(&mut self, mode: GuardMode) {
+        self.guard_mode = mode;
+    }
+
+    /// Allocations caught in `GuardMode::Log`. Read it from a control
+    /// thread to log them, since the audio thread doesn't.
+    #[cfg(feature = "rt-guard")]
+    pub fn guard_report(&self) -> Arc<GuardReport> {
+        Arc::clone(&self.guard_report)
+    }
+
+    /// Run a node at the graph's sample rate divided by `divisor`, putting it
+    /// in a different rate domain. Connections between nodes in different
+    /// domains are resampled.
+    ///
+    /// # Panics
+    /// If the divisor is zero
+    pub fn set_rate_divisor(&mut self, id: Index, divisor: usize) {
+        assert!(divisor > 0, "Rate divisor must be greater than zero!");
+
+        let sample_rate = self.sample_rate;
+        let buffer_size = self.buffer_size();
+
+        if let Some(node) = self.arena.get_mut(id) {
+            node.rate_divisor = divisor;
+            Self::prepare_node(sample_rate, buffer_size, node);
+        }
+
+        self.update_resamplers();
+        self.update_latency();
+    }
+
+    // Add resamplers to connections that cross between rate domains,
+    // and remove them from connections that don't.
+    fn update_resamplers(&mut self) {
+        for i in 0..self.ordering.len() {
+            let id = self.ordering[i];
+
+            if let Some((current, mut rest)) = split_at(&mut self.arena, id) {
+                let from = current.rate_divisor;
+                let output_ports = &current.output_ports;
+
+                for connection in current.connections.iter_mut() {
+                    // Only the channels both ports have are mixed
+                    let sources = port_range(output_ports, connection.source_port);
+                    let target = rest.get_mut(connection.id).and_then(|node| {
+                        let targets = port_range(&node.input_ports, connection.target_port);
+
+                        match (sources, targets) {
+                            (Some((_, sources)), Some((_, targets))) => {
+                                Some((node.rate_divisor, sources.min(targets)))
+                            }
+                            _ => None,
+                        }
+                    });
+
+                    connection.resampler = match target {
+                        Some((to, channels)) if to != from => match connection.resampler.take() {
+                            Some(resampler) if resampler.matches(from, to, channels) => {
+                                Some(resampler)
+                            }
+                            _ => Some(Resampler::new(from, to, channels)),
+                        },
+                        _ => None,
+                    };
+                }
+            }
+        }
     }
 
     pub fn has_cycles(&mut self) -> bool {
@@ -393,9 +1516,15 @@ where
 mod tests {
     use alloc_counter::{deny_alloc, AllocCounterSystem};
 
+    #[cfg(not(feature = "rt-guard"))]
     #[global_allocator]
     static A: AllocCounterSystem = AllocCounterSystem;
 
+    #[cfg(feature = "rt-guard")]
+    #[global_allocator]
+    static A: crate::guard::GuardedAllocator<AllocCounterSystem> =
+        crate::guard::GuardedAllocator::new(AllocCounterSystem);
+
     use super::*;
     use crate::route::Route;
     use bufferpool::BufferPoolReference;
@@ -403,7 +1532,7 @@ mod tests {
 
     struct TestRoute;
 
-    trait AnyRoute<S: sample::Sample>: Route<S> {
+    trait AnyRoute<S: sample::Sample>: Route<S> + Send {
         fn as_any(&self) -> &dyn Any;
     }
 
@@ -533,6 +1662,84 @@ mod tests {
         }
     }
 
+    struct LatencyRoute(usize);
+
+    impl Route<S> for LatencyRoute {
+        type Context = ();
+
+        fn process(
+            &mut self,
+            _input: &[BufferPoolReference<S>],
+            _output: &mut [BufferPoolReference<S>],
+            _frames: usize,
+            _context: &mut Self::Context,
+        ) {
+        }
+
+        fn latency(&self) -> usize {
+            self.0
+        }
+    }
+
+    impl AnyRoute<S> for LatencyRoute {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    // Turns the input down by the level of the sidechain
+    struct DuckRoute;
+
+    impl Route<S> for DuckRoute {
+        type Context = ();
+
+        fn process(
+            &mut self,
+            input: &[BufferPoolReference<S>],
+            output: &mut [BufferPoolReference<S>],
+            frames: usize,
+            context: &mut Self::Context,
+        ) {
+            self.process_sidechain(input, &[], output, frames, context);
+        }
+
+        fn process_sidechain(
+            &mut self,
+            input: &[BufferPoolReference<S>],
+            sidechain: &[BufferPoolReference<S>],
+            output: &mut [BufferPoolReference<S>],
+            frames: usize,
+            _context: &mut Self::Context,
+        ) {
+            for (channel, output) in output.iter_mut().enumerate() {
+                for i in 0..frames {
+                    let input = input.get(channel).map(|b| b.as_ref()[i]).unwrap_or(0.);
+                    let key = sidechain.first().map(|b| b.as_ref()[i]).unwrap_or(0.);
+
+                    output.as_mut()[i] = input * (1. - key.abs());
+                }
+            }
+        }
+    }
+
+    impl AnyRoute<S> for DuckRoute {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    impl AnyRoute<S> for SubGraph<S, R> {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    impl AnyRoute<S> for VoiceGroup<S, R> {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
     impl AnyRoute<S> for Box<dyn AnyRoute<S, Context = ()>> {
         fn as_any(&self) -> &dyn Any {
             (**self).as_any()
@@ -551,6 +1758,41 @@ mod tests {
         ) {
             (**self).process(input, output, frames, context);
         }
+
+        fn process_sidechain(
+            &mut self,
+            input: &[BufferPoolReference<S>],
+            sidechain: &[BufferPoolReference<S>],
+            output: &mut [BufferPoolReference<S>],
+            frames: usize,
+            context: &mut C,
+        ) {
+            (**self).process_sidechain(input, sidechain, output, frames, context);
+        }
+
+        fn overwrites_output(&self) -> bool {
+            (**self).overwrites_output()
+        }
+
+        fn latency(&self) -> usize {
+            (**self).latency()
+        }
+
+        fn tail(&self) -> Tail {
+            (**self).tail()
+        }
+
+        fn prepare(&mut self, sample_rate: f64, max_block: usize, channels: usize) {
+            (**self).prepare(sample_rate, max_block, channels);
+        }
+
+        fn reset(&mut self) {
+            (**self).reset();
+        }
+
+        fn release(&mut self) {
+            (**self).release();
+        }
     }
 
     fn create_node(id: Index, mut connections: Vec<Index>) -> N {
@@ -706,6 +1948,8 @@ mod tests {
             )
         });
 
+        graph.topographic_sort();
+
         let mut c = ();
 
         deny_alloc(|| {
@@ -731,6 +1975,40 @@ mod tests {
         assert_eq!(output, test);
     }
 
+    #[test]
+    fn test_blocks_are_split_into_buffer_sized_parts() {
+        let mut graph: RouteGraph<S, R> = RouteGraphBuilder::new().with_buffer_size(32).build();
+
+        let counter = graph.add_node_with_idx(|id| {
+            Node::with_id(id, 1, Box::new(CountingNode { current: 0 }), vec![])
+        });
+
+        graph.set_outputs(&[counter]);
+        graph.topographic_sort();
+
+        let mut output = vec![0.; 80];
+
+        deny_alloc(|| {
+            graph.process_with_io(&[], &mut [&mut output], &mut ());
+        });
+
+        // 32 + 32 + 16 frames, without processing anything twice
+        let expected: Vec<f32> = (0..80).map(|i| i as f32).collect();
+
+        assert_eq!(output, expected);
+        assert_eq!(graph.clock, 80);
+
+        let processed = graph.with_node_mut(counter, |node| {
+            node.route()
+                .as_any()
+                .downcast_ref::<CountingNode>()
+                .unwrap()
+                .current
+        });
+
+        assert_eq!(processed, Some(80));
+    }
+
     #[test]
     fn test_simple_topo_sort() {
         let mut graph: RouteGraph<S, R> = RouteGraphBuilder::new().with_buffer_size(32).build();
@@ -768,4 +2046,2283 @@ mod tests {
         assert_eq!(graph.has_cycles(), false);
         assert_eq!(graph.ordering.clone(), vec![a, b, c, d, e, f,]);
     }
+
+    #[test]
+    fn test_sub_graph_signal_flow() {
+        let mut inner: RouteGraph<S, R> = RouteGraphBuilder::new().with_buffer_size(32).build();
+
+        let inner_output = inner.add_node_with_idx(|id| create_node(id, vec![]));
+        let inner_input = inner.add_node_with_idx(|id| {
+            Node::with_id(
+                id,
+                1,
+                Box::new(TestRoute),
+                vec![Connection::new(inner_output, 0.5)],
+            )
+        });
+
+        inner.topographic_sort();
+
+        let mut graph: RouteGraph<S, R> = RouteGraphBuilder::new().with_buffer_size(32).build();
+
+        let output = graph.add_node_with_idx(|id| {
+            Node::with_id(
+                id,
+                1,
+                Box::new(OutputRoute {
+                    output: vec![0.; 32],
+                    position: 0,
+                }),
+                vec![],
+            )
+        });
+
+        let mut sub = Some(SubGraph::new(inner, inner_input, inner_output));
+
+        let group = graph.add_node_with_idx(|id| {
+            Node::with_id(
+                id,
+                1,
+                Box::new(sub.take().unwrap()),
+                vec![Connection::new(output, 1.)],
+            )
+        });
+
+        graph.add_node_with_idx(|id| {
+            Node::with_id(
+                id,
+                1,
+                Box::new(InputRoute {
+                    input: vec![1.; 32],
+                }),
+                vec![Connection::new(group, 1.)],
+            )
+        });
+
+        graph.topographic_sort();
+
+        let mut c = ();
+
+        deny_alloc(|| {
+            graph.process(32, &mut c);
+        });
+
+        let output = graph
+            .with_node_mut(output, |node| {
+                node.route()
+                    .as_any()
+                    .downcast_ref::<OutputRoute>()
+                    .unwrap()
+                    .output
+                    .clone()
+            })
+            .unwrap();
+
+        assert_eq!(output, vec![0.5; 32]);
+    }
+
+    #[test]
+    fn test_removing_nodes_updates_latency() {
+        let mut graph: RouteGraph<S, R> = RouteGraphBuilder::new().with_buffer_size(32).build();
+
+        let output = graph.add_node_with_idx(|id| create_node(id, vec![]));
+        let long = graph.add_node_with_idx(|id| {
+            Node::with_id(
+                id,
+                1,
+                Box::new(LatencyRoute(10)),
+                vec![Connection::new(output, 1.)],
+            )
+        });
+        graph.add_node_with_idx(|id| {
+            Node::with_id(
+                id,
+                1,
+                Box::new(LatencyRoute(2)),
+                vec![Connection::new(output, 1.)],
+            )
+        });
+
+        graph.topographic_sort();
+
+        assert_eq!(graph.latency(), 10);
+
+        graph.remove_node(long);
+
+        assert_eq!(graph.latency(), 2);
+    }
+
+    #[test]
+    fn test_sub_graph_latency() {
+        let mut inner: RouteGraph<S, R> = RouteGraphBuilder::new().with_buffer_size(32).build();
+
+        let output =
+            inner.add_node_with_idx(|id| Node::with_id(id, 1, Box::new(LatencyRoute(4)), vec![]));
+        let long = inner.add_node_with_idx(|id| {
+            Node::with_id(
+                id,
+                1,
+                Box::new(LatencyRoute(10)),
+                vec![Connection::new(output, 1.)],
+            )
+        });
+        let short = inner.add_node_with_idx(|id| {
+            Node::with_id(
+                id,
+                1,
+                Box::new(LatencyRoute(2)),
+                vec![Connection::new(output, 1.)],
+            )
+        });
+        let input = inner.add_node_with_idx(|id| {
+            Node::with_id(
+                id,
+                1,
+                Box::new(LatencyRoute(1)),
+                vec![Connection::new(long, 1.), Connection::new(short, 1.)],
+            )
+        });
+
+        inner.topographic_sort();
+
+        let sub = SubGraph::new(inner, input, output);
+
+        // Routes can ask for the latency while processing
+        let latency = deny_alloc(|| sub.latency());
+
+        assert_eq!(latency, 15);
+    }
+
+    #[test]
+    fn test_sub_graph_uses_parent_buffer_size() {
+        let mut inner: RouteGraph<S, R> = RouteGraphBuilder::new().with_buffer_size(32).build();
+
+        let counter = inner.add_node_with_idx(|id| {
+            Node::with_id(id, 1, Box::new(CountingNode { current: 0 }), vec![])
+        });
+
+        inner.topographic_sort();
+
+        let mut sub = Some(SubGraph::new(inner, counter, counter));
+
+        let mut graph: RouteGraph<S, R> = RouteGraphBuilder::new().with_buffer_size(64).build();
+
+        let group = graph
+            .add_node_with_idx(|id| Node::with_id(id, 1, Box::new(sub.take().unwrap()), vec![]));
+
+        graph.set_outputs(&[group]);
+        graph.topographic_sort();
+
+        let buffer_size = graph.with_node_mut(group, |node| {
+            let sub = node.route().as_any().downcast_ref::<SubGraph<S, R>>();
+            sub.unwrap().graph().buffer_size()
+        });
+
+        assert_eq!(buffer_size, Some(64));
+
+        let mut output = vec![0.; 64];
+
+        deny_alloc(|| {
+            graph.process_with_io(&[], &mut [&mut output], &mut ());
+        });
+
+        let expected: Vec<f32> = (0..64).map(|i| i as f32).collect();
+
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn test_patch_round_trip() {
+        let mut graph: RouteGraph<S, R> = RouteGraphBuilder::new().with_buffer_size(32).build();
+
+        let b = graph.add_node_with_idx(|id| create_node(id, vec![]));
+        let a = graph.add_node_with_idx(|id| create_node(id, vec![b]));
+
+        graph.with_node_mut(a, |node| node.set_label("input"));
+        graph.set_rate_divisor(b, 2);
+        graph.set_sink(b, true);
+
+        let sub = SubGraph::new(graph, a, b);
+        let patch = sub.to_patch(|_| "test");
+
+        let rebuilt: SubGraph<S, R> =
+            SubGraph::from_patch(patch.clone(), 32, |_| -> R { Box::new(TestRoute) });
+
+        assert_eq!(rebuilt.graph().len(), 2);
+        assert_eq!(patch.nodes.len(), 2);
+
+        let connections = rebuilt
+            .graph()
+            .with_node(rebuilt.input(), |node| {
+                node.connections.iter().map(|c| c.id()).collect::<Vec<_>>()
+            })
+            .unwrap();
+
+        assert_eq!(connections, vec![rebuilt.output()]);
+
+        let graph = rebuilt.graph();
+
+        assert_eq!(graph.node_by_label("input"), Some(rebuilt.input()));
+        assert_eq!(
+            graph.with_node(rebuilt.output(), |node| (node.rate_divisor, node.sink)),
+            Some((2, true))
+        );
+        assert_eq!(rebuilt.to_patch(|_| "test"), patch);
+    }
+
+    #[test]
+    fn test_process_with_io() {
+        let mut graph: RouteGraph<S, R> = RouteGraphBuilder::new().with_buffer_size(32).build();
+
+        let output = graph.add_node_with_idx(|id| create_node(id, vec![]));
+        let input = graph.add_node_with_idx(|id| {
+            Node::with_id(
+                id,
+                1,
+                Box::new(TestRoute),
+                vec![Connection::new(output, 0.5)],
+            )
+        });
+
+        graph.set_inputs(&[input]);
+        graph.set_outputs(&[output]);
+        graph.topographic_sort();
+
+        let input: Vec<f32> = (0..64).map(|i| i as f32).collect();
+        let mut output = vec![0.; 64];
+
+        let mut c = ();
+
+        deny_alloc(|| {
+            graph.process_with_io(&[&input], &mut [&mut output], &mut c);
+        });
+
+        let expected: Vec<f32> = input.iter().map(|x| x * 0.5).collect();
+
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn test_declarative_builder() {
+        let route = || -> R { Box::new(TestRoute) };
+
+        let mut graph = RouteGraphBuilder::<S, R>::new()
+            .with_buffer_size(32)
+            .with_node("output", 1, route())
+            .with_node("gain", 1, route())
+            .with_node("input", 1, route())
+            .with_connection("input", "gain", 0.25)
+            .with_connection("input", "gain", 0.5)
+            .with_connection("gain", "output", 0.5)
+            .with_input("input")
+            .with_output("output")
+            .build();
+
+        let input = graph.node_by_label("input").unwrap();
+        let gain = graph.node_by_label("gain").unwrap();
+        let output = graph.node_by_label("output").unwrap();
+
+        assert_eq!(graph.ordering, vec![input, gain, output]);
+        assert_eq!(graph.node_by_label("missing"), None);
+
+        let input: Vec<f32> = (0..32).map(|i| i as f32).collect();
+        let mut output = vec![0.; 32];
+
+        let mut c = ();
+
+        deny_alloc(|| {
+            graph.process_with_io(&[&input], &mut [&mut output], &mut c);
+        });
+
+        let expected: Vec<f32> = input.iter().map(|x| x * 0.25).collect();
+
+        assert_eq!(output, expected);
+
+        let error = |builder: RouteGraphBuilder<S, R>| builder.try_build().err().unwrap();
+
+        assert_eq!(
+            error(
+                RouteGraphBuilder::new()
+                    .with_node("a", 1, route())
+                    .with_node("a", 1, route())
+            ),
+            BuildError::DuplicateLabel(String::from("a"))
+        );
+
+        assert_eq!(
+            error(
+                RouteGraphBuilder::new()
+                    .with_node("a", 1, route())
+                    .with_output("b")
+            ),
+            BuildError::UnknownLabel(String::from("b"))
+        );
+
+        assert_eq!(
+            error(
+                RouteGraphBuilder::new()
+                    .with_node("a", 1, route())
+                    .with_connection("a", "b", 1.)
+            ),
+            BuildError::DanglingConnection {
+                source: String::from("a"),
+                target: String::from("b")
+            }
+        );
+
+        assert_eq!(
+            error(
+                RouteGraphBuilder::new()
+                    .with_node("a", 1, route())
+                    .with_node("b", 2, route())
+                    .with_connection("a", "b", 1.)
+            ),
+            BuildError::ChannelMismatch {
+                source: String::from("a"),
+                target: String::from("b")
+            }
+        );
+
+        // Outputs are checked against inputs
+        assert_eq!(
+            error(
+                RouteGraphBuilder::new()
+                    .with_io_node("a", 1, 2, route())
+                    .with_node("b", 1, route())
+                    .with_connection("a", "b", 1.)
+            ),
+            BuildError::ChannelMismatch {
+                source: String::from("a"),
+                target: String::from("b")
+            }
+        );
+
+        let mono_to_stereo = RouteGraphBuilder::new()
+            .with_node("a", 1, route())
+            .with_io_node("b", 1, 2, route())
+            .with_node("c", 2, route())
+            .with_connection("a", "b", 1.)
+            .with_connection("b", "c", 1.)
+            .try_build();
+
+        assert!(mono_to_stereo.is_ok());
+
+        let cycle = error(
+            RouteGraphBuilder::new()
+                .with_node("a", 1, route())
+                .with_node("b", 1, route())
+                .with_node("c", 1, route())
+                .with_connection("a", "b", 1.)
+                .with_connection("b", "c", 1.)
+                .with_connection("c", "b", 1.),
+        );
+
+        match cycle {
+            BuildError::Cycle(label) => assert!(label == "b" || label == "c"),
+            error => panic!("Expected a cycle, got {:?}", error),
+        }
+    }
+
+    #[test]
+    fn test_builder_prepares_routes_once() {
+        use std::sync::Mutex;
+
+        struct PrepareRoute(Arc<Mutex<Vec<f64>>>);
+
+        impl Route<S> for PrepareRoute {
+            type Context = ();
+
+            fn process(
+                &mut self,
+                _input: &[BufferPoolReference<S>],
+                _output: &mut [BufferPoolReference<S>],
+                _frames: usize,
+                _context: &mut Self::Context,
+            ) {
+            }
+
+            fn prepare(&mut self, sample_rate: f64, _max_block: usize, _channels: usize) {
+                self.0.lock().unwrap().push(sample_rate);
+            }
+        }
+
+        impl AnyRoute<S> for PrepareRoute {
+            fn as_any(&self) -> &dyn Any {
+                self
+            }
+        }
+
+        let prepared = Arc::new(Mutex::new(vec![]));
+
+        RouteGraphBuilder::<S, R>::new()
+            .with_sample_rate(48_000.)
+            .with_node("a", 1, Box::new(PrepareRoute(Arc::clone(&prepared))))
+            .build();
+
+        assert_eq!(*prepared.lock().unwrap(), vec![48_000.]);
+    }
+
+    #[test]
+    fn test_typed_node_handles() {
+        let mut graph: RouteGraph<S, R> = RouteGraphBuilder::new().with_buffer_size(32).build();
+        let mut other: RouteGraph<S, R> = RouteGraphBuilder::new().with_buffer_size(32).build();
+
+        let output = graph.add_node(|id| create_node(id, vec![]));
+        let input = graph.add_node(|id| create_node(id, vec![]));
+        let foreign = other.add_node(|id| create_node(id, vec![]));
+
+        graph.connect(input.output(), output.input(), 0.5);
+        graph.set_inputs(&[input.index()]);
+        graph.set_outputs(&[output.index()]);
+        graph.topographic_sort();
+
+        assert_eq!(graph.node_id(input.index()), Some(input));
+        assert!(graph.contains(output));
+        assert!(!graph.contains(foreign));
+
+        let samples: Vec<f32> = (0..32).map(|i| i as f32).collect();
+        let mut result = vec![0.; 32];
+
+        let mut c = ();
+
+        graph.process_with_io(&[&samples], &mut [&mut result], &mut c);
+
+        let expected: Vec<f32> = samples.iter().map(|x| x * 0.5).collect();
+        assert_eq!(result, expected);
+
+        let mismatch = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            graph.connect(input.output(), foreign.input(), 1.)
+        }));
+
+        assert!(mismatch.is_err());
+
+        graph.disconnect(input.output(), output.input());
+        assert_eq!(
+            graph.with_node(input.index(), |n| n.connections.len()),
+            Some(0)
+        );
+
+        assert!(graph.remove(input).is_some());
+        assert!(!graph.contains(input));
+        assert_eq!(graph.node_id(input.index()), None);
+    }
+
+    #[test]
+    fn test_named_ports() {
+        let mut graph: RouteGraph<S, R> = RouteGraphBuilder::new().with_buffer_size(32).build();
+
+        let source = graph.add_node(|id| {
+            N::with_id(id, 2, Box::new(TestRoute), vec![])
+                .with_output_ports(vec![Port::new("main", 1), Port::new("aux", 1)])
+        });
+
+        let target = graph.add_node(|id| {
+            N::with_id(id, 3, Box::new(TestRoute), vec![])
+                .with_input_ports(vec![Port::new("main", 2), Port::new("sidechain", 1)])
+        });
+
+        let aux = graph.output_port(source, "aux").unwrap();
+        let sidechain = graph.input_port(target, "sidechain").unwrap();
+
+        assert_eq!((aux.port(), sidechain.port()), (1, 1));
+        assert!(graph.output_port(source, "missing").is_none());
+
+        graph.connect(source.output(), target.input(), 1.);
+        graph.connect(aux, sidechain, 0.5);
+        graph.set_inputs(&[source.index()]);
+        graph.set_outputs(&[target.index()]);
+        graph.topographic_sort();
+
+        let main_input = vec![1.; 32];
+        let aux_input = vec![2.; 32];
+        let mut outputs = vec![vec![0.; 32]; 3];
+
+        let mut c = ();
+
+        {
+            let (first, rest) = outputs.split_at_mut(1);
+            let (second, third) = rest.split_at_mut(1);
+
+            deny_alloc(|| {
+                graph.process_with_io(
+                    &[&main_input, &aux_input],
+                    &mut [&mut first[0], &mut second[0], &mut third[0]],
+                    &mut c,
+                );
+            });
+        }
+
+        assert_eq!(outputs[0], vec![1.; 32]);
+        assert_eq!(outputs[1], vec![0.; 32]);
+        assert_eq!(outputs[2], vec![1.; 32]);
+
+        let patch = graph.to_patch(|_| ());
+        let connection = &patch
+            .nodes
+            .iter()
+            .find(|n| n.channels == 2)
+            .unwrap()
+            .connections;
+
+        assert_eq!(connection.len(), 2);
+        assert!(connection
+            .iter()
+            .any(|c| (c.source_port, c.target_port, c.amount) == (1, 1, 0.5)));
+
+        graph.disconnect(aux, sidechain);
+
+        assert_eq!(
+            graph.with_node(source.index(), |node| node.connections.len()),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn test_sidechain_input() {
+        let mut graph: RouteGraph<S, R> = RouteGraphBuilder::new().with_buffer_size(32).build();
+
+        let ducker = graph.add_node(|id| {
+            N::with_id(id, 1, Box::new(DuckRoute), vec![]).with_sidechain("sidechain", 1)
+        });
+
+        let music = graph.add_node(|id| {
+            Node::with_id(
+                id,
+                1,
+                Box::new(InputRoute {
+                    input: vec![1.; 32],
+                }),
+                vec![],
+            )
+        });
+
+        let key = graph.add_node(|id| {
+            Node::with_id(
+                id,
+                1,
+                Box::new(InputRoute {
+                    input: vec![0.5; 32],
+                }),
+                vec![],
+            )
+        });
+
+        let sidechain = graph.input_port(ducker, "sidechain").unwrap();
+
+        graph.connect(music.output(), ducker.input(), 1.);
+        graph.connect(key.output(), sidechain, 1.);
+        graph.set_outputs(&[ducker.index()]);
+        graph.topographic_sort();
+
+        let position =
+            |id: NodeId<RouteGraph<S, R>>| graph.ordering.iter().position(|i| *i == id.index());
+
+        assert!(position(key) < position(ducker));
+        assert!(position(music) < position(ducker));
+
+        let mut output = vec![0.; 32];
+        let mut c = ();
+
+        deny_alloc(|| {
+            graph.process_with_io(&[], &mut [&mut output], &mut c);
+        });
+
+        assert_eq!(output, vec![0.5; 32]);
+
+        // Without a key the music isn't ducked
+        graph.disconnect(key.output(), sidechain);
+
+        deny_alloc(|| {
+            graph.process_with_io(&[], &mut [&mut output], &mut c);
+        });
+
+        assert_eq!(output, vec![1.; 32]);
+    }
+
+    #[test]
+    fn test_named_sidechains() {
+        let node = N::with_id(Index::from_raw_parts(0, 0), 1, Box::new(DuckRoute), vec![])
+            .with_sidechain("key", 1)
+            .with_sidechain("trigger", 2);
+
+        assert_eq!(node.input_port("key"), Some(1));
+        assert_eq!(node.input_port("trigger"), Some(2));
+        assert_eq!(node.sidechain_channels(), 3);
+
+        let duplicate = std::panic::catch_unwind(|| {
+            N::with_id(Index::from_raw_parts(0, 0), 1, Box::new(DuckRoute), vec![])
+                .with_sidechain("key", 1)
+                .with_sidechain("key", 1)
+        });
+
+        assert!(duplicate.is_err());
+    }
+
+    #[test]
+    fn test_separate_input_and_output_channels() {
+        struct PanRoute;
+
+        impl Route<S> for PanRoute {
+            type Context = ();
+
+            fn process(
+                &mut self,
+                input: &[BufferPoolReference<S>],
+                output: &mut [BufferPoolReference<S>],
+                frames: usize,
+                _context: &mut Self::Context,
+            ) {
+                assert_eq!(output.len(), 2);
+
+                if let Some(input) = input.first() {
+                    for (gain, output) in [0.25, 0.75].iter().zip(output.iter_mut()) {
+                        for (output, input) in output.as_mut()[..frames]
+                            .iter_mut()
+                            .zip(input.as_ref().iter())
+                        {
+                            *output = input * gain;
+                        }
+                    }
+                }
+            }
+        }
+
+        impl AnyRoute<S> for PanRoute {
+            fn as_any(&self) -> &dyn Any {
+                self
+            }
+        }
+
+        let mut graph: RouteGraph<S, R> = RouteGraphBuilder::new().with_buffer_size(32).build();
+
+        let sink = graph.add_node(|id| N::with_id(id, 2, Box::new(TestRoute), vec![]));
+        let pan = graph.add_node(|id| {
+            N::with_id(
+                id,
+                1,
+                Box::new(PanRoute),
+                vec![Connection::new(sink.index(), 1.)],
+            )
+            .with_output_channels(2)
+        });
+        graph.add_node(|id| {
+            Node::with_id(
+                id,
+                1,
+                Box::new(InputRoute {
+                    input: vec![1.; 32],
+                }),
+                vec![Connection::new(pan.index(), 1.)],
+            )
+        });
+
+        graph.set_outputs(&[sink.index()]);
+        graph.topographic_sort();
+
+        assert_eq!(
+            graph.with_node(pan.index(), |n| (n.input_channels(), n.output_channels())),
+            Some((1, 2))
+        );
+
+        let tap = graph.attach_tap(pan.index(), 32).unwrap();
+        assert_eq!(tap.channels(), 2);
+
+        let mut left = vec![0.; 32];
+        let mut right = vec![0.; 32];
+        let mut c = ();
+
+        deny_alloc(|| {
+            graph.process_with_io(&[], &mut [&mut left, &mut right], &mut c);
+        });
+
+        // The source's signal is split across the pan node's two outputs
+        assert_eq!(left, vec![0.25; 32]);
+        assert_eq!(right, vec![0.75; 32]);
+    }
+
+    #[test]
+    fn test_outputs_are_cleared_between_nodes() {
+        struct SilentRoute;
+
+        impl Route<S> for SilentRoute {
+            type Context = ();
+
+            fn process(
+                &mut self,
+                _input: &[BufferPoolReference<S>],
+                _output: &mut [BufferPoolReference<S>],
+                _frames: usize,
+                _context: &mut Self::Context,
+            ) {
+            }
+        }
+
+        impl AnyRoute<S> for SilentRoute {
+            fn as_any(&self) -> &dyn Any {
+                self
+            }
+        }
+
+        let mut graph: RouteGraph<S, R> = RouteGraphBuilder::new().with_buffer_size(32).build();
+
+        let sink = graph.add_node(|id| create_node(id, vec![]));
+        let silent = graph.add_node(|id| {
+            N::with_id(
+                id,
+                1,
+                Box::new(SilentRoute),
+                vec![Connection::new(sink.index(), 1.)],
+            )
+        });
+        let loud = graph.add_node(|id| {
+            Node::with_id(
+                id,
+                1,
+                Box::new(InputRoute {
+                    input: vec![1.; 32],
+                }),
+                vec![],
+            )
+        });
+
+        // The silent node ignores its input, this only makes sure the
+        // loud node is processed before it
+        graph.connect(loud.output(), silent.input(), 1.);
+        graph.set_outputs(&[sink.index()]);
+        graph.topographic_sort();
+
+        let position =
+            |id: NodeId<RouteGraph<S, R>>| graph.ordering.iter().position(|i| *i == id.index());
+
+        assert!(position(loud) < position(silent));
+
+        let mut output = vec![1.; 32];
+        let mut c = ();
+
+        deny_alloc(|| {
+            graph.process_with_io(&[], &mut [&mut output], &mut c);
+        });
+
+        assert_eq!(output, vec![0.; 32]);
+    }
+
+    #[test]
+    fn test_incoming_connections() {
+        let mut graph: RouteGraph<S, R> = RouteGraphBuilder::new().with_buffer_size(32).build();
+
+        let d = graph.add_node_with_idx(|id| create_node(id, vec![]));
+        let c = graph.add_node_with_idx(|id| create_node(id, vec![d]));
+        let b = graph.add_node_with_idx(|id| create_node(id, vec![c]));
+        let a = graph.add_node_with_idx(|id| create_node(id, vec![c]));
+
+        let sorted = |iter: &mut dyn Iterator<Item = Index>| {
+            let mut ids: Vec<Index> = iter.collect();
+            ids.sort();
+            ids
+        };
+
+        let mut expected = vec![a, b];
+        expected.sort();
+
+        assert_eq!(sorted(&mut graph.inputs_of(c)), expected);
+        assert_eq!(graph.outputs_of(c).collect::<Vec<_>>(), vec![d]);
+        assert_eq!(graph.inputs_of(a).count(), 0);
+
+        let (source, target) = (graph.node_id(a).unwrap(), graph.node_id(c).unwrap());
+        graph.disconnect(source.output(), target.input());
+
+        assert_eq!(graph.inputs_of(c).collect::<Vec<_>>(), vec![b]);
+        assert_eq!(graph.outputs_of(a).count(), 0);
+
+        graph.remove_node(c);
+
+        assert_eq!(graph.outputs_of(b).count(), 0);
+        assert_eq!(graph.inputs_of(d).count(), 0);
+        assert_eq!(graph.inputs_of(c).count(), 0);
+
+        let mut topology = graph.topology();
+        topology.connect(a, d, 1.);
+        topology.connect(b, d, 1.);
+        graph.swap_topology(topology.build().unwrap());
+
+        let mut context = ();
+        graph.process(32, &mut context);
+
+        let mut expected = vec![a, b];
+        expected.sort();
+
+        assert_eq!(sorted(&mut graph.inputs_of(d)), expected);
+
+        graph.with_node_connections(b, |connections| connections.clear());
+
+        assert_eq!(graph.inputs_of(d).collect::<Vec<_>>(), vec![a]);
+
+        graph.with_node_connections(a, |connections| {
+            connections.push(Connection::new(b, 1.));
+        });
+
+        assert_eq!(graph.inputs_of(b).collect::<Vec<_>>(), vec![a]);
+        assert_eq!(graph.inputs_of(d).collect::<Vec<_>>(), vec![a]);
+    }
+
+    #[test]
+    fn test_queries() {
+        let mut graph: RouteGraph<S, R> = RouteGraphBuilder::new().with_buffer_size(32).build();
+
+        let d = graph.add_node_with_idx(|id| create_node(id, vec![]));
+        let c = graph.add_node_with_idx(|id| create_node(id, vec![d]).with_sidechain("key", 1));
+        let b = graph.add_node_with_idx(|id| create_node(id, vec![c, d]));
+        let a = graph.add_node_with_idx(|id| create_node(id, vec![b]));
+        let e = graph.add_node_with_idx(|id| create_node(id, vec![]));
+
+        let (source, target) = (graph.node_id(b).unwrap(), graph.node_id(c).unwrap());
+        graph.connect(source.output(), target.input(), 0.5);
+        graph.connect(
+            source.output(),
+            graph.input_port(target, "key").unwrap(),
+            0.25,
+        );
+        graph.topographic_sort();
+
+        assert_eq!(graph.nodes().count(), 5);
+        assert_eq!(graph.ordering().len(), 5);
+        assert_eq!(graph.ordering().last(), Some(&d));
+
+        let key = |edge: &(Index, Index, S, usize, usize)| (edge.0, edge.1, edge.4);
+
+        let mut edges: Vec<(Index, Index, S, usize, usize)> = graph.edges().collect();
+        edges.sort_by_key(key);
+
+        // Connections between the same nodes on different ports are separate
+        let mut expected = vec![
+            (a, b, 1., 0, 0),
+            (b, c, 0.5, 0, 0),
+            (b, c, 0.25, 0, 1),
+            (b, d, 1., 0, 0),
+            (c, d, 1., 0, 0),
+        ];
+        expected.sort_by_key(key);
+
+        assert_eq!(edges, expected);
+
+        let mut upstream = graph.upstream(d);
+        upstream.sort();
+
+        let mut expected = vec![a, b, c];
+        expected.sort();
+
+        assert_eq!(upstream, expected);
+        assert_eq!(graph.downstream(c), vec![d]);
+        assert!(graph.downstream(e).is_empty());
+
+        assert_eq!(graph.path(a, d), Some(vec![a, b, d]));
+        assert_eq!(graph.path(a, a), Some(vec![a]));
+        assert_eq!(graph.path(d, a), None);
+        assert!(graph.is_reachable(a, c));
+        assert!(!graph.is_reachable(e, d));
+    }
+
+    #[test]
+    fn test_dead_and_unreachable_nodes() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        struct EffectRoute(Arc<AtomicUsize>);
+
+        impl Route<S> for EffectRoute {
+            type Context = ();
+
+            fn process(
+                &mut self,
+                _input: &[BufferPoolReference<S>],
+                _output: &mut [BufferPoolReference<S>],
+                _frames: usize,
+                _context: &mut Self::Context,
+            ) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        impl AnyRoute<S> for EffectRoute {
+            fn as_any(&self) -> &dyn Any {
+                self
+            }
+        }
+
+        let unreachable_count = Arc::new(AtomicUsize::new(0));
+        let dead_count = Arc::new(AtomicUsize::new(0));
+
+        let mut graph: RouteGraph<S, R> = RouteGraphBuilder::new().with_buffer_size(32).build();
+
+        let output = graph.add_node_with_idx(|id| create_node(id, vec![]));
+
+        let mut route = Some(EffectRoute(Arc::clone(&unreachable_count)));
+        let unreachable = graph.add_node_with_idx(|id| {
+            Node::with_id(
+                id,
+                1,
+                Box::new(route.take().unwrap()),
+                vec![Connection::new(output, 1.)],
+            )
+        });
+
+        let mut route = Some(EffectRoute(Arc::clone(&dead_count)));
+        let dead = graph
+            .add_node_with_idx(|id| Node::with_id(id, 1, Box::new(route.take().unwrap()), vec![]));
+
+        let source = graph.add_node_with_idx(|id| {
+            Node::with_id(
+                id,
+                1,
+                Box::new(CountingNode { current: 1 }),
+                vec![Connection::new(output, 1.), Connection::new(dead, 1.)],
+            )
+        });
+
+        graph.set_outputs(&[output]);
+        graph.topographic_sort();
+
+        // Nothing is reached until the node that makes sound is marked
+        let mut unreachable_nodes = graph.unreachable_nodes();
+        unreachable_nodes.sort();
+
+        let mut expected = vec![output, unreachable, dead, source];
+        expected.sort();
+
+        assert_eq!(unreachable_nodes, expected);
+
+        graph.set_source(source, true);
+
+        assert_eq!(graph.unreachable_nodes(), vec![unreachable]);
+        assert_eq!(graph.dead_nodes(), vec![dead]);
+
+        // Nothing is skipped until pruning is turned on
+        assert!(!graph.is_pruned(dead));
+
+        let mut c = ();
+        let mut buffer = vec![0.; 32];
+
+        graph.process_with_io(&[], &mut [&mut buffer], &mut c);
+
+        assert_eq!(unreachable_count.load(Ordering::Relaxed), 1);
+        assert_eq!(dead_count.load(Ordering::Relaxed), 1);
+
+        graph.set_pruning(true);
+
+        assert!(graph.is_pruned(unreachable));
+        assert!(graph.is_pruned(dead));
+        assert!(!graph.is_pruned(source));
+        assert!(!graph.is_pruned(output));
+
+        graph.process_with_io(&[], &mut [&mut buffer], &mut c);
+
+        assert_eq!(unreachable_count.load(Ordering::Relaxed), 1);
+        assert_eq!(dead_count.load(Ordering::Relaxed), 1);
+        assert_eq!(buffer[31], 64.);
+
+        // Marking a node as a sink brings it back
+        graph.set_sink(dead, true);
+
+        assert!(graph.dead_nodes().is_empty());
+        assert!(!graph.is_pruned(dead));
+
+        graph.process_with_io(&[], &mut [&mut buffer], &mut c);
+
+        assert_eq!(unreachable_count.load(Ordering::Relaxed), 1);
+        assert_eq!(dead_count.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_history_undo_and_redo() {
+        let mut graph: RouteGraph<S, R> = RouteGraphBuilder::new().with_buffer_size(32).build();
+
+        let c = graph.add_node_with_idx(|id| create_node(id, vec![]));
+        let b = graph.add_node_with_idx(|id| create_node(id, vec![c]));
+        let a = graph.add_node_with_idx(|id| create_node(id, vec![b]));
+
+        graph.set_outputs(&[c]);
+        graph.topographic_sort();
+
+        let edges = |graph: &RouteGraph<S, R>| {
+            let mut edges: Vec<(Index, Index, S)> = graph
+                .edges()
+                .map(|(source, target, amount, _, _)| (source, target, amount))
+                .collect();
+            edges.sort_by_key(|(source, target, _)| (*source, *target));
+            edges
+        };
+
+        let mut history = History::new();
+
+        let d = history.add_node(&mut graph, |id| create_node(id, vec![c]));
+        let (source, target) = (graph.node_id(a).unwrap(), graph.node_id(b).unwrap());
+        history.connect(&mut graph, source.output(), target.input(), 0.5);
+        history.change(
+            &mut graph,
+            b,
+            |node| node.set_label("changed"),
+            |node| node.label = None,
+        );
+
+        assert!(history.remove_node(&mut graph, b));
+        assert!(!history.remove_node(&mut graph, b));
+        assert_eq!(graph.len(), 3);
+
+        // The removed node comes back with its connections and a new index
+        assert!(history.undo(&mut graph));
+
+        let restored = history.current(b);
+
+        assert_ne!(restored, b);
+        assert_eq!(
+            graph.with_node(restored, |node| node.label().map(String::from)),
+            Some(Some("changed".to_string()))
+        );
+
+        let mut expected = vec![(a, restored, 0.5), (restored, c, 1.), (d, c, 1.)];
+        expected.sort_by_key(|(source, target, _)| (*source, *target));
+
+        assert_eq!(edges(&graph), expected);
+
+        history.undo(&mut graph);
+        assert_eq!(
+            graph.with_node(restored, |node| node.label().is_none()),
+            Some(true)
+        );
+
+        history.undo(&mut graph);
+        history.undo(&mut graph);
+
+        assert_eq!(graph.len(), 3);
+        assert!(!history.can_undo());
+        assert!(!history.undo(&mut graph));
+
+        let mut expected = vec![(a, restored, 1.), (restored, c, 1.)];
+        expected.sort_by_key(|(source, target, _)| (*source, *target));
+
+        assert_eq!(edges(&graph), expected);
+
+        while history.redo(&mut graph) {}
+
+        let d = history.current(d);
+
+        assert_eq!(graph.len(), 3);
+        assert_eq!(edges(&graph), vec![(d, c, 1.)]);
+
+        graph.topographic_sort();
+        assert_eq!(graph.ordering().len(), 3);
+
+        // Making a new edit forgets the edits that were undone
+        history.undo(&mut graph);
+        let (source, target) = (graph.node_id(d).unwrap(), graph.node_id(c).unwrap());
+        history.disconnect(&mut graph, source.output(), target.input());
+
+        assert!(!history.can_redo());
+        assert!(graph.edges().all(|(source, ..)| source != d));
+    }
+
+    #[test]
+    fn test_voice_group() {
+        use std::sync::atomic::{AtomicU32, Ordering};
+
+        struct LevelRoute(Arc<AtomicU32>);
+
+        impl Route<S> for LevelRoute {
+            type Context = ();
+
+            fn process(
+                &mut self,
+                _input: &[BufferPoolReference<S>],
+                output: &mut [BufferPoolReference<S>],
+                frames: usize,
+                _context: &mut Self::Context,
+            ) {
+                let level = f32::from_bits(self.0.load(Ordering::Relaxed));
+
+                for sample in output[0].as_mut().iter_mut().take(frames) {
+                    *sample = level;
+                }
+            }
+        }
+
+        impl AnyRoute<S> for LevelRoute {
+            fn as_any(&self) -> &dyn Any {
+                self
+            }
+        }
+
+        let levels: Vec<Arc<AtomicU32>> = (0..2).map(|_| Arc::new(AtomicU32::new(0))).collect();
+
+        let voices = levels
+            .iter()
+            .map(|level| {
+                let mut graph: RouteGraph<S, R> =
+                    RouteGraphBuilder::new().with_buffer_size(32).build();
+
+                let mut route = Some(LevelRoute(Arc::clone(level)));
+                let node = graph.add_node_with_idx(|id| {
+                    Node::with_id(id, 1, Box::new(route.take().unwrap()), vec![])
+                });
+
+                graph.topographic_sort();
+
+                SubGraph::new(graph, node, node)
+            })
+            .collect();
+
+        let handler_levels = levels.clone();
+        let mut group = VoiceGroup::new(voices, 32).with_handler(move |voice, _, event| {
+            let level = match event {
+                NoteEvent::On { velocity, .. } => velocity,
+                NoteEvent::Off { .. } => 0.,
+            };
+
+            handler_levels[voice].store(level.to_bits(), Ordering::Relaxed);
+        });
+
+        let mut pool: BufferPool<S> = BufferPoolBuilder::new()
+            .with_capacity(1)
+            .with_buffer_size(32)
+            .build();
+
+        let mut output = [pool.get_cleared_space().unwrap()];
+        let mut c = ();
+
+        let mut process = |group: &mut VoiceGroup<S, R>| {
+            for sample in output[0].as_mut().iter_mut() {
+                *sample = 0.;
+            }
+
+            group.process(&[], &mut output, 32, &mut c);
+            output[0].as_ref()[31]
+        };
+
+        deny_alloc(|| {
+            assert_eq!(group.note_on(60, 0.5), Some(0));
+            assert_eq!(group.note_on(64, 0.25), Some(1));
+            assert_eq!(process(&mut group), 0.75);
+
+            // The oldest note loses its voice
+            assert_eq!(group.note_on(67, 1.), Some(0));
+            assert_eq!(group.voice_note(0), Some(67));
+            assert_eq!(process(&mut group), 1.25);
+
+            // Released voices sleep once they're silent
+            group.note_off(67);
+            assert_eq!(process(&mut group), 0.25);
+            assert_eq!(group.active_voices(), 1);
+            assert_eq!(group.voice_note(0), None);
+        });
+
+        let mut group = group.with_stealing(Stealing::Quietest);
+
+        deny_alloc(|| {
+            assert_eq!(group.note_on(72, 1.), Some(0));
+            assert_eq!(process(&mut group), 1.25);
+
+            assert_eq!(group.note_on(76, 0.5), Some(1));
+            assert_eq!(group.voice_note(1), Some(76));
+        });
+
+        let mut group = group.with_stealing(Stealing::SameNote);
+
+        deny_alloc(|| {
+            group.note_off(72);
+            assert_eq!(process(&mut group), 0.5);
+
+            // The note that's still playing is restarted rather than
+            // using the free voice
+            assert_eq!(group.note_on(76, 0.75), Some(1));
+            assert_eq!(process(&mut group), 0.75);
+            assert_eq!(group.active_voices(), 1);
+        });
+    }
+
+    #[test]
+    fn test_voice_group_plays_queued_notes() {
+        use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+        struct LevelRoute {
+            level: Arc<AtomicU32>,
+            resets: Arc<AtomicUsize>,
+        }
+
+        impl Route<S> for LevelRoute {
+            type Context = ();
+
+            fn process(
+                &mut self,
+                _input: &[BufferPoolReference<S>],
+                output: &mut [BufferPoolReference<S>],
+                frames: usize,
+                _context: &mut Self::Context,
+            ) {
+                let level = f32::from_bits(self.level.load(Ordering::Relaxed));
+
+                for sample in output[0].as_mut().iter_mut().take(frames) {
+                    *sample = level;
+                }
+            }
+
+            fn reset(&mut self) {
+                self.resets.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        impl AnyRoute<S> for LevelRoute {
+            fn as_any(&self) -> &dyn Any {
+                self
+            }
+        }
+
+        let level = Arc::new(AtomicU32::new(0));
+        let resets = Arc::new(AtomicUsize::new(0));
+
+        let mut graph: RouteGraph<S, R> = RouteGraphBuilder::new().with_buffer_size(32).build();
+
+        let mut route = Some(LevelRoute {
+            level: Arc::clone(&level),
+            resets: Arc::clone(&resets),
+        });
+
+        let node = graph
+            .add_node_with_idx(|id| Node::with_id(id, 1, Box::new(route.take().unwrap()), vec![]));
+
+        graph.topographic_sort();
+
+        let handler_level = Arc::clone(&level);
+        let mut group = VoiceGroup::new(vec![SubGraph::new(graph, node, node)], 32).with_handler(
+            move |_, _, event| {
+                if let NoteEvent::On { velocity, .. } = event {
+                    handler_level.store(velocity.to_bits(), Ordering::Relaxed);
+                }
+            },
+        );
+
+        let mut sender = group.note_sender(2);
+
+        let mut pool: BufferPool<S> = BufferPoolBuilder::new()
+            .with_capacity(1)
+            .with_buffer_size(80)
+            .build();
+
+        let mut output = [pool.get_cleared_space().unwrap()];
+        let mut c = ();
+
+        deny_alloc(|| {
+            assert_eq!(sender.note_on(60, 0.5), Ok(()));
+            assert_eq!(sender.note_on(64, 0.25), Ok(()));
+            assert_eq!(
+                sender.note_on(67, 1.),
+                Err(NoteEvent::On {
+                    note: 67,
+                    velocity: 1.
+                })
+            );
+
+            // Blocks longer than the group's scratch space are split rather
+            // than cut short
+            group.process(&[], &mut output, 80, &mut c);
+            assert!(output[0].as_ref().iter().all(|sample| *sample == 0.25));
+
+            // The second note steals the only voice without resetting its routes
+            assert_eq!(group.voice_note(0), Some(64));
+            assert_eq!(resets.load(Ordering::Relaxed), 0);
+        });
+    }
+
+    #[test]
+    fn test_slow_terminal_nodes() {
+        let mut graph: RouteGraph<S, R> = RouteGraphBuilder::new().with_buffer_size(32).build();
+
+        let node = graph.add_node_with_idx(|id| create_node(id, vec![]));
+
+        graph.set_rate_divisor(node, 2);
+        graph.set_inputs(&[node]);
+        graph.set_outputs(&[node]);
+        graph.topographic_sort();
+
+        let input: Vec<S> = (0..32).map(|i| i as S).collect();
+        let mut output = vec![0.; 32];
+
+        let mut c = ();
+
+        graph.process_with_io(&[&input], &mut [&mut output], &mut c);
+
+        // Every other frame is read, and each one is held for two frames
+        let expected: Vec<S> = (0..32).map(|i| (i / 2 * 2) as S).collect();
+
+        assert_eq!(output, expected);
+
+        // Starting halfway through one of the node's frames
+        graph.process_with_io(&[&input[..3]], &mut [&mut output[..3]], &mut c);
+        graph.process_with_io(&[&input[3..8]], &mut [&mut output[3..8]], &mut c);
+
+        assert_eq!(&output[3..8], &[4., 4., 4., 6., 6.]);
+    }
+
+    #[test]
+    fn test_sub_graph_sidechain() {
+        let mut inner: RouteGraph<S, R> = RouteGraphBuilder::new().with_buffer_size(32).build();
+
+        let ducker = inner.add_node(|id| {
+            N::with_id(id, 1, Box::new(DuckRoute), vec![]).with_sidechain("sidechain", 1)
+        });
+        let input = inner.add_node(|id| create_node(id, vec![]));
+        let key = inner.add_node(|id| create_node(id, vec![]));
+
+        let sidechain = inner.input_port(ducker, "sidechain").unwrap();
+
+        inner.connect(input.output(), ducker.input(), 1.);
+        inner.connect(key.output(), sidechain, 1.);
+        inner.topographic_sort();
+
+        let mut sub =
+            Some(SubGraph::new(inner, input.index(), ducker.index()).with_sidechain(key.index()));
+
+        let mut graph: RouteGraph<S, R> = RouteGraphBuilder::new().with_buffer_size(32).build();
+
+        let group = graph.add_node(|id| {
+            N::with_id(id, 1, Box::new(sub.take().unwrap()), vec![]).with_sidechain("sidechain", 1)
+        });
+
+        let music = graph.add_node(|id| {
+            Node::with_id(
+                id,
+                1,
+                Box::new(InputRoute {
+                    input: vec![1.; 32],
+                }),
+                vec![],
+            )
+        });
+
+        let level = graph.add_node(|id| {
+            Node::with_id(
+                id,
+                1,
+                Box::new(InputRoute {
+                    input: vec![0.5; 32],
+                }),
+                vec![],
+            )
+        });
+
+        let sidechain = graph.input_port(group, "sidechain").unwrap();
+
+        graph.connect(music.output(), group.input(), 1.);
+        graph.connect(level.output(), sidechain, 1.);
+        graph.set_outputs(&[group.index()]);
+        graph.topographic_sort();
+
+        let mut output = vec![0.; 32];
+
+        deny_alloc(|| {
+            graph.process_with_io(&[], &mut [&mut output], &mut ());
+        });
+
+        assert_eq!(output, vec![0.5; 32]);
+    }
+
+    #[test]
+    fn test_history_restores_terminal_order() {
+        let mut graph: RouteGraph<S, R> = RouteGraphBuilder::new().with_buffer_size(32).build();
+
+        let left = graph.add_node_with_idx(|id| create_node(id, vec![]));
+        let right = graph.add_node_with_idx(|id| create_node(id, vec![]));
+
+        graph.set_inputs(&[left, right]);
+        graph.set_outputs(&[left, right]);
+
+        let mut history = History::new();
+
+        assert!(history.remove_node(&mut graph, left));
+        assert!(history.undo(&mut graph));
+
+        let left = history.current(left);
+
+        assert_eq!(graph.inputs, vec![left, right]);
+        assert_eq!(graph.outputs, vec![left, right]);
+
+        graph.topographic_sort();
+
+        let mut outputs = (vec![0.; 32], vec![0.; 32]);
+        graph.process_with_io(
+            &[&[1.; 32], &[0.5; 32]],
+            &mut [&mut outputs.0, &mut outputs.1],
+            &mut (),
+        );
+
+        assert_eq!(outputs, (vec![1.; 32], vec![0.5; 32]));
+    }
+
+    #[test]
+    fn test_swap_topology_waits_for_retired_topology() {
+        let mut graph: RouteGraph<S, R> = RouteGraphBuilder::new().with_buffer_size(32).build();
+
+        let b = graph.add_node_with_idx(|id| create_node(id, vec![]));
+        let a = graph.add_node_with_idx(|id| create_node(id, vec![b]));
+
+        graph.topographic_sort();
+
+        let mut c = ();
+
+        let mut topology = graph.topology();
+        topology.connect(a, b, 0.5);
+        graph.swap_topology(topology.build().unwrap());
+
+        deny_alloc(|| graph.process(32, &mut c));
+
+        let mut topology = graph.topology();
+        topology.disconnect(a, b);
+        graph.swap_topology(topology.build().unwrap());
+
+        // The first retired topology hasn't been taken, so the second swap
+        // waits rather than dropping it
+        deny_alloc(|| graph.process(32, &mut c));
+
+        assert!(graph.is_swapping_topology());
+        assert_eq!(graph.outputs_of(a).collect::<Vec<_>>(), vec![b]);
+
+        assert!(graph.retired_topology().is_some());
+
+        deny_alloc(|| graph.process(32, &mut c));
+
+        assert!(!graph.is_swapping_topology());
+        assert_eq!(graph.outputs_of(a).count(), 0);
+        assert!(graph.retired_topology().is_some());
+    }
+
+    #[test]
+    fn test_rate_domains_resample_connections() {
+        let mut graph: RouteGraph<S, R> = RouteGraphBuilder::new().with_buffer_size(64).build();
+
+        let output = graph.add_node_with_idx(|id| {
+            Node::with_id(
+                id,
+                1,
+                Box::new(OutputRoute {
+                    output: vec![0.; 256],
+                    position: 0,
+                }),
+                vec![],
+            )
+        });
+
+        let slow = graph.add_node_with_idx(|id| create_node(id, vec![output]));
+
+        graph.add_node_with_idx(|id| {
+            Node::with_id(
+                id,
+                1,
+                Box::new(InputRoute {
+                    input: vec![1.; 64],
+                }),
+                vec![Connection::new(slow, 1.)],
+            )
+        });
+
+        graph.set_rate_divisor(slow, 3);
+        graph.topographic_sort();
+
+        assert_eq!(graph.latency(), 2 * 24);
+
+        let mut c = ();
+
+        deny_alloc(|| {
+            for _ in 0..4 {
+                graph.process(64, &mut c);
+            }
+        });
+
+        let output = graph
+            .with_node_mut(output, |node| {
+                node.route()
+                    .as_any()
+                    .downcast_ref::<OutputRoute>()
+                    .unwrap()
+                    .output
+                    .clone()
+            })
+            .unwrap();
+
+        for sample in output[192..].iter() {
+            assert!((sample - 1.).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn test_rate_domains_resample_mono_into_stereo() {
+        let mut graph: RouteGraph<S, R> = RouteGraphBuilder::new().with_buffer_size(16).build();
+
+        let output = graph.add_node_with_idx(|id| {
+            Node::with_id(
+                id,
+                1,
+                Box::new(OutputRoute {
+                    output: vec![0.; 256],
+                    position: 0,
+                }),
+                vec![],
+            )
+        });
+
+        // Only the first of the stereo node's channels is fed and read.
+        // The blocks are shorter than the resampling filters, so their
+        // history has to carry over between blocks.
+        let slow = graph.add_node_with_idx(|id| {
+            Node::with_id(
+                id,
+                2,
+                Box::new(TestRoute),
+                vec![Connection::new(output, 1.)],
+            )
+        });
+
+        graph.add_node_with_idx(|id| {
+            Node::with_id(
+                id,
+                1,
+                Box::new(InputRoute {
+                    input: vec![1.; 16],
+                }),
+                vec![Connection::new(slow, 1.)],
+            )
+        });
+
+        graph.set_rate_divisor(slow, 3);
+        graph.topographic_sort();
+
+        let mut c = ();
+
+        deny_alloc(|| {
+            for _ in 0..16 {
+                graph.process(16, &mut c);
+            }
+        });
+
+        let output = graph
+            .with_node_mut(output, |node| {
+                node.route()
+                    .as_any()
+                    .downcast_ref::<OutputRoute>()
+                    .unwrap()
+                    .output
+                    .clone()
+            })
+            .unwrap();
+
+        for sample in output[192..].iter() {
+            assert!((sample - 1.).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn test_profiling_records_node_times() {
+        let mut graph: RouteGraph<S, R> = RouteGraphBuilder::new().with_buffer_size(32).build();
+
+        let a = graph.add_node_with_idx(|id| create_node(id, vec![]));
+
+        let profile = graph.enable_profiling(4);
+
+        let b = graph.add_node_with_idx(|id| create_node(id, vec![a]));
+
+        graph.topographic_sort();
+
+        let mut c = ();
+
+        deny_alloc(|| {
+            for _ in 0..6 {
+                graph.process(32, &mut c);
+            }
+        });
+
+        assert_eq!(profile.time().count(), 6);
+        assert_eq!(profile.time().recent().len(), 4);
+        assert!(profile.load() > 0.);
+
+        for id in [a, b].iter() {
+            let node = graph.node_profile(*id).unwrap();
+            assert_eq!(node.count(), 6);
+            assert!(node.min() <= node.average() && node.average() <= node.max());
+        }
+
+        // A block split into parts is still recorded once
+        graph.process(96, &mut c);
+
+        assert_eq!(profile.time().count(), 7);
+        assert_eq!(graph.node_profile(a).unwrap().count(), 7);
+
+        profile.reset();
+
+        assert_eq!(profile.time().count(), 0);
+        assert!(profile.time().recent().is_empty());
+
+        graph.disable_profiling();
+
+        assert!(graph.node_profile(a).is_none());
+    }
+
+    #[test]
+    fn test_connection_meters_measure_one_port() {
+        let mut graph: RouteGraph<S, R> = RouteGraphBuilder::new().with_buffer_size(32).build();
+
+        let b = graph.add_node(|id| create_node(id, vec![]));
+        let a = graph.add_node(|id| {
+            N::with_id(
+                id,
+                1,
+                Box::new(InputRoute {
+                    input: vec![1.; 32],
+                }),
+                vec![],
+            )
+            .with_output_channels(2)
+            .with_output_ports(vec![Port::new("left", 1), Port::new("right", 1)])
+        });
+
+        let (left, right) = (
+            graph.output_port(a, "left").unwrap(),
+            graph.output_port(a, "right").unwrap(),
+        );
+
+        graph.connect(left, b.input(), 0.5);
+        graph.connect(right, b.input(), 0.25);
+        graph.topographic_sort();
+
+        let meter = graph.attach_connection_meter(right, b.input()).unwrap();
+
+        deny_alloc(|| graph.process(32, &mut ()));
+
+        assert_eq!(meter.channels(), 1);
+        assert!((meter.peak(0) - 0.25).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_meters_measure_nodes_and_connections() {
+        let mut graph: RouteGraph<S, R> = RouteGraphBuilder::new()
+            .with_buffer_size(64)
+            .with_sample_rate(64_000.)
+            .build();
+
+        // A 1kHz sine at half scale, which should be about -9 LUFS
+        let input: Vec<S> = (0..64)
+            .map(|i| 0.5 * (2. * std::f32::consts::PI * i as f32 / 64.).sin())
+            .collect();
+
+        let b = graph.add_node_with_idx(|id| create_node(id, vec![]));
+
+        let a = graph.add_node_with_idx(|id| {
+            Node::with_id(
+                id,
+                1,
+                Box::new(InputRoute {
+                    input: input.clone(),
+                }),
+                vec![Connection::new(b, 0.5)],
+            )
+        });
+
+        graph.topographic_sort();
+
+        let node = graph.attach_meter(a).unwrap();
+
+        let (source, target) = (graph.node_id(a).unwrap(), graph.node_id(b).unwrap());
+        let connection = graph
+            .attach_connection_meter(source.output(), target.input())
+            .unwrap();
+
+        let mut c = ();
+
+        deny_alloc(|| {
+            // Half a second of audio
+            for _ in 0..500 {
+                graph.process(64, &mut c);
+            }
+        });
+
+        assert_eq!(node.channels(), 1);
+        assert!((node.peak(0) - 0.5).abs() < 1e-6);
+        assert!((node.rms(0) - 0.5 / 2f64.sqrt()).abs() < 1e-3);
+        assert!(node.true_peak(0) >= node.peak(0));
+        assert!((connection.peak(0) - 0.25).abs() < 1e-6);
+
+        for loudness in [node.momentary(0), node.short_term(0), node.integrated(0)].iter() {
+            assert!((loudness + 9.03).abs() < 0.1);
+        }
+
+        assert!((connection.momentary(0) + 15.05).abs() < 0.1);
+
+        graph.detach_meter(a);
+        graph.detach_connection_meter(source.output(), target.input());
+    }
+
+    #[test]
+    fn test_tap_captures_node_output() {
+        let mut graph: RouteGraph<S, R> = RouteGraphBuilder::new().with_buffer_size(32).build();
+
+        let input: Vec<S> = (0..32).map(|i| i as S).collect();
+
+        let a = graph.add_node_with_idx(|id| {
+            Node::with_id(
+                id,
+                1,
+                Box::new(InputRoute {
+                    input: input.clone(),
+                }),
+                vec![],
+            )
+        });
+
+        graph.topographic_sort();
+
+        let mut tap = graph.attach_tap(a, 48).unwrap();
+
+        let mut c = ();
+
+        deny_alloc(|| {
+            graph.process(32, &mut c);
+            graph.process(32, &mut c);
+        });
+
+        assert_eq!(tap.available(), 48);
+        assert_eq!(tap.overflows(), 16);
+
+        let mut output = vec![0.; 64];
+
+        assert_eq!(tap.read(&mut output), 48);
+        assert_eq!(&output[..32], &input[..]);
+        assert_eq!(&output[32..48], &input[..16]);
+        assert_eq!(tap.available(), 0);
+    }
+
+    #[cfg(feature = "rt-guard")]
+    #[test]
+    fn test_guard_names_allocating_node() {
+        struct AllocatingRoute;
+
+        impl Route<S> for AllocatingRoute {
+            type Context = ();
+
+            fn process(
+                &mut self,
+                _input: &[BufferPoolReference<S>],
+                _output: &mut [BufferPoolReference<S>],
+                frames: usize,
+                _context: &mut Self::Context,
+            ) {
+                drop(vec![0.; frames]);
+            }
+        }
+
+        impl AnyRoute<S> for AllocatingRoute {
+            fn as_any(&self) -> &dyn Any {
+                self
+            }
+        }
+
+        let mut graph: RouteGraph<S, R> = RouteGraphBuilder::new().with_buffer_size(32).build();
+
+        let a =
+            graph.add_node_with_idx(|id| Node::with_id(id, 1, Box::new(AllocatingRoute), vec![]));
+
+        graph.topographic_sort();
+
+        // Logging leaves the report for another thread to read
+        let report = graph.guard_report();
+
+        graph.process(32, &mut ());
+
+        let violation = report.take().unwrap();
+
+        assert_eq!(violation.node, Some(a));
+        assert_eq!(violation.allocations, 1);
+        assert_eq!(violation.deallocations, 1);
+        assert_eq!(report.violations(), 1);
+        assert!(report.take().is_none());
+
+        graph.set_guard_mode(crate::guard::GuardMode::Panic);
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            graph.process(32, &mut ());
+        }));
+
+        let message = result.unwrap_err().downcast::<String>().unwrap();
+
+        assert!(message.contains(&format!("node {:?}", a)));
+        assert!(message.contains("1 allocations and 1 deallocations"));
+    }
+
+    #[test]
+    fn test_swap_topology() {
+        let mut graph: RouteGraph<S, R> = RouteGraphBuilder::new().with_buffer_size(32).build();
+
+        let out = graph.add_node_with_idx(|id| {
+            Node::with_id(
+                id,
+                1,
+                Box::new(OutputRoute {
+                    output: vec![0.; 32],
+                    position: 0,
+                }),
+                vec![],
+            )
+        });
+
+        let b = graph.add_node_with_idx(|id| create_node(id, vec![]));
+
+        let a = graph.add_node_with_idx(|id| {
+            Node::with_id(
+                id,
+                1,
+                Box::new(InputRoute {
+                    input: vec![1.; 32],
+                }),
+                vec![Connection::new(out, 1.)],
+            )
+        });
+
+        graph.topographic_sort();
+
+        let output = |graph: &mut RouteGraph<S, R>| {
+            graph
+                .with_node_mut(out, |node| {
+                    node.route()
+                        .as_any()
+                        .downcast_ref::<OutputRoute>()
+                        .unwrap()
+                        .output
+                        .clone()
+                })
+                .unwrap()
+        };
+
+        let mut cyclic = graph.topology();
+        cyclic.connect(out, a, 1.);
+
+        assert!(cyclic.build::<R>().is_err());
+
+        let mut topology = graph.topology();
+        topology.disconnect(a, out);
+        topology.connect(a, b, 1.);
+        topology.connect(b, out, 0.5);
+
+        assert!(graph.swap_topology(topology.build().unwrap()).is_none());
+        assert!(graph.retired_topology().is_none());
+
+        let mut c = ();
+
+        deny_alloc(|| {
+            graph.process(32, &mut c);
+        });
+
+        assert_eq!(output(&mut graph), vec![0.5; 32]);
+        assert!(graph.retired_topology().is_some());
+
+        let mut topology = graph.topology().with_crossfade(64);
+        topology.remove_node(b);
+        topology.connect(a, out, 1.);
+
+        graph.swap_topology(topology.build().unwrap());
+
+        let mut blocks = vec![];
+
+        for _ in 0..2 {
+            deny_alloc(|| {
+                graph.process(32, &mut c);
+            });
+
+            assert!(graph.is_swapping_topology());
+            assert_eq!(graph.len(), 3);
+
+            blocks.push(output(&mut graph));
+        }
+
+        // The old path through b fades out while the new one fades in
+        for (block, samples) in blocks.iter().enumerate() {
+            for (i, sample) in samples.iter().enumerate() {
+                let gain = (block * 32 + i) as f32 / 64.;
+                assert!((sample - (0.5 * (1. - gain) + gain)).abs() < 1e-6);
+            }
+        }
+
+        graph.process(32, &mut c);
+
+        assert!(!graph.is_swapping_topology());
+        assert_eq!(output(&mut graph), vec![1.; 32]);
+        assert_eq!(graph.len(), 2);
+
+        let removed = graph.retired_topology().unwrap().into_removed_nodes();
+
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].id(), b);
+    }
+
+    #[test]
+    fn test_crossfade_terminals() {
+        let mut graph: RouteGraph<S, R> = RouteGraphBuilder::new().with_buffer_size(32).build();
+
+        let a = graph.add_node_with_idx(|id| create_node(id, vec![]));
+        let b = graph.add_node_with_idx(|id| create_node(id, vec![]));
+
+        graph.set_inputs(&[a]);
+        graph.set_outputs(&[a]);
+        graph.topographic_sort();
+
+        let mut topology = graph.topology().with_crossfade(32);
+        topology.connect(a, b, 0.5);
+        topology.set_outputs(&[b]);
+
+        graph.swap_topology(topology.build().unwrap());
+
+        let input = vec![1.; 32];
+        let mut output = vec![0.; 32];
+
+        let mut c = ();
+
+        deny_alloc(|| {
+            graph.process_with_io(&[&input], &mut [&mut output], &mut c);
+        });
+
+        // The old output fades out as the new one fades in
+        for (i, sample) in output.iter().enumerate() {
+            let gain = i as f32 / 32.;
+            assert!((sample - ((1. - gain) + 0.5 * gain)).abs() < 1e-6);
+        }
+
+        graph.process_with_io(&[&input], &mut [&mut output], &mut c);
+
+        assert!(!graph.is_swapping_topology());
+        assert_eq!(output, vec![0.5; 32]);
+    }
+
+    #[test]
+    fn test_faded_node_insertion_and_removal() {
+        let mut graph: RouteGraph<S, R> = RouteGraphBuilder::new().with_buffer_size(32).build();
+
+        let out = graph.add_node_with_idx(|id| {
+            Node::with_id(
+                id,
+                1,
+                Box::new(OutputRoute {
+                    output: vec![0.; 32],
+                    position: 0,
+                }),
+                vec![],
+            )
+        });
+
+        let a = graph.add_node_faded(64, |id| {
+            Node::with_id(
+                id,
+                1,
+                Box::new(InputRoute {
+                    input: vec![1.; 32],
+                }),
+                vec![Connection::new(out, 1.)],
+            )
+        });
+
+        graph.topographic_sort();
+
+        let output = |graph: &mut RouteGraph<S, R>| {
+            graph
+                .with_node_mut(out, |node| {
+                    node.route()
+                        .as_any()
+                        .downcast_ref::<OutputRoute>()
+                        .unwrap()
+                        .output
+                        .clone()
+                })
+                .unwrap()
+        };
+
+        let mut c = ();
+        let mut blocks = vec![];
+
+        for _ in 0..3 {
+            deny_alloc(|| {
+                graph.process(32, &mut c);
+            });
+
+            blocks.push(output(&mut graph));
+        }
+
+        assert_eq!(blocks[0][16], 16. / 64.);
+        assert_eq!(blocks[1][16], 48. / 64.);
+        assert_eq!(blocks[2], vec![1.; 32]);
+
+        graph.remove_node_faded(a, 64);
+
+        deny_alloc(|| {
+            graph.process(32, &mut c);
+        });
+
+        assert_eq!(output(&mut graph)[16], 1. - 16. / 64.);
+        assert!(graph.collect_faded().is_empty());
+
+        deny_alloc(|| {
+            graph.process(32, &mut c);
+        });
+
+        assert_eq!(output(&mut graph)[16], 1. - 48. / 64.);
+
+        let removed = graph.collect_faded();
+
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].id(), a);
+        assert_eq!(graph.len(), 1);
+    }
+
+    #[test]
+    fn test_nodes_sleep_after_tail() {
+        use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+        struct GateRoute(Arc<AtomicBool>);
+
+        impl Route<S> for GateRoute {
+            type Context = ();
+
+            fn process(
+                &mut self,
+                _input: &[BufferPoolReference<S>],
+                output: &mut [BufferPoolReference<S>],
+                frames: usize,
+                _context: &mut Self::Context,
+            ) {
+                let value = if self.0.load(Ordering::Relaxed) {
+                    1.
+                } else {
+                    0.
+                };
+
+                for sample in output[0].as_mut().iter_mut().take(frames) {
+                    *sample = value;
+                }
+            }
+        }
+
+        impl AnyRoute<S> for GateRoute {
+            fn as_any(&self) -> &dyn Any {
+                self
+            }
+        }
+
+        struct TailRoute(Arc<AtomicUsize>);
+
+        impl Route<S> for TailRoute {
+            type Context = ();
+
+            fn process(
+                &mut self,
+                _input: &[BufferPoolReference<S>],
+                _output: &mut [BufferPoolReference<S>],
+                _frames: usize,
+                _context: &mut Self::Context,
+            ) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+
+            fn tail(&self) -> Tail {
+                Tail::Frames(40)
+            }
+        }
+
+        impl AnyRoute<S> for TailRoute {
+            fn as_any(&self) -> &dyn Any {
+                self
+            }
+        }
+
+        let open = Arc::new(AtomicBool::new(true));
+        let processed = Arc::new(AtomicUsize::new(0));
+
+        let mut graph: RouteGraph<S, R> = RouteGraphBuilder::new().with_buffer_size(32).build();
+
+        let mut route = Some(TailRoute(Arc::clone(&processed)));
+        let b = graph
+            .add_node_with_idx(|id| Node::with_id(id, 1, Box::new(route.take().unwrap()), vec![]));
+
+        let mut route = Some(GateRoute(Arc::clone(&open)));
+        graph.add_node_with_idx(|id| {
+            Node::with_id(
+                id,
+                1,
+                Box::new(route.take().unwrap()),
+                vec![Connection::new(b, 1.)],
+            )
+        });
+
+        graph.topographic_sort();
+
+        let mut c = ();
+        let asleep =
+            |graph: &RouteGraph<S, R>| graph.with_node(b, |node| node.is_asleep()).unwrap();
+
+        deny_alloc(|| {
+            graph.process(32, &mut c);
+        });
+
+        open.store(false, Ordering::Relaxed);
+
+        deny_alloc(|| {
+            // The first 40 silent frames are still processed
+            for _ in 0..4 {
+                graph.process(32, &mut c);
+            }
+        });
+
+        assert_eq!(processed.load(Ordering::Relaxed), 3);
+        assert!(asleep(&graph));
+
+        graph.wake_node(b);
+        graph.process(32, &mut c);
+
+        assert_eq!(processed.load(Ordering::Relaxed), 4);
+
+        open.store(true, Ordering::Relaxed);
+        graph.process(32, &mut c);
+
+        assert_eq!(processed.load(Ordering::Relaxed), 5);
+        assert!(!asleep(&graph));
+    }
+
+    #[test]
+    fn test_route_lifecycle() {
+        #[derive(Default)]
+        struct LifecycleRoute {
+            prepared: Option<(f64, usize, usize)>,
+            resets: usize,
+            released: bool,
+        }
+
+        impl Route<S> for LifecycleRoute {
+            type Context = ();
+
+            fn process(
+                &mut self,
+                _input: &[BufferPoolReference<S>],
+                _output: &mut [BufferPoolReference<S>],
+                _frames: usize,
+                _context: &mut Self::Context,
+            ) {
+            }
+
+            fn prepare(&mut self, sample_rate: f64, max_block: usize, channels: usize) {
+                self.prepared = Some((sample_rate, max_block, channels));
+            }
+
+            fn reset(&mut self) {
+                self.resets += 1;
+            }
+
+            fn release(&mut self) {
+                self.released = true;
+            }
+        }
+
+        impl AnyRoute<S> for LifecycleRoute {
+            fn as_any(&self) -> &dyn Any {
+                self
+            }
+        }
+
+        fn state(node: &mut N) -> (Option<(f64, usize, usize)>, usize, bool) {
+            let route = node
+                .route()
+                .as_any()
+                .downcast_ref::<LifecycleRoute>()
+                .unwrap();
+
+            (route.prepared, route.resets, route.released)
+        }
+
+        let mut graph: RouteGraph<S, R> = RouteGraphBuilder::new().with_buffer_size(32).build();
+
+        let a = graph.add_node_with_idx(|id| {
+            Node::with_id(id, 2, Box::new(LifecycleRoute::default()), vec![])
+        });
+
+        assert_eq!(
+            graph.with_node_mut(a, state).unwrap(),
+            (Some((44_100., 32, 2)), 0, false)
+        );
+
+        graph.set_rate_divisor(a, 2);
+        graph.set_buffer_size(64);
+        graph.silence_all_buffers();
+
+        assert_eq!(
+            graph.with_node_mut(a, state).unwrap(),
+            (Some((22_050., 32, 2)), 1, false)
+        );
+
+        let mut node = graph.remove_node(a).unwrap();
+
+        assert!(state(&mut node).2);
+    }
+
+    #[test]
+    fn test_set_sample_rate() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        struct RateRoute(Arc<AtomicU64>);
+
+        impl Route<S> for RateRoute {
+            type Context = ();
+
+            fn process(
+                &mut self,
+                _input: &[BufferPoolReference<S>],
+                _output: &mut [BufferPoolReference<S>],
+                _frames: usize,
+                _context: &mut Self::Context,
+            ) {
+            }
+
+            fn prepare(&mut self, sample_rate: f64, _max_block: usize, _channels: usize) {
+                self.0.store(sample_rate.to_bits(), Ordering::Relaxed);
+            }
+        }
+
+        impl AnyRoute<S> for RateRoute {
+            fn as_any(&self) -> &dyn Any {
+                self
+            }
+        }
+
+        let rate = Arc::new(AtomicU64::new(0));
+        let inner_rate = Arc::new(AtomicU64::new(0));
+        let load = |rate: &Arc<AtomicU64>| f64::from_bits(rate.load(Ordering::Relaxed));
+
+        let mut inner: RouteGraph<S, R> = RouteGraphBuilder::new().with_buffer_size(32).build();
+
+        let mut route = Some(RateRoute(Arc::clone(&inner_rate)));
+        let inner_node = inner
+            .add_node_with_idx(|id| Node::with_id(id, 1, Box::new(route.take().unwrap()), vec![]));
+
+        let mut graph: RouteGraph<S, R> = RouteGraphBuilder::new()
+            .with_buffer_size(32)
+            .with_sample_rate(48_000.)
+            .build();
+
+        let mut route = Some(RateRoute(Arc::clone(&rate)));
+        let a = graph
+            .add_node_with_idx(|id| Node::with_id(id, 1, Box::new(route.take().unwrap()), vec![]));
+
+        let mut sub_graph = Some(SubGraph::new(inner, inner_node, inner_node));
+        graph.add_node_with_idx(|id| {
+            Node::with_id(id, 1, Box::new(sub_graph.take().unwrap()), vec![])
+        });
+
+        assert_eq!(graph.sample_rate(), 48_000.);
+        assert_eq!(load(&rate), 48_000.);
+        assert_eq!(load(&inner_rate), 48_000.);
+
+        graph.set_rate_divisor(a, 2);
+        graph.set_sample_rate(96_000.);
+
+        assert_eq!(load(&rate), 48_000.);
+        assert_eq!(load(&inner_rate), 96_000.);
+    }
 }
@@ -1,30 +1,189 @@
+use super::fade::{Fade, Side};
+use super::meter::Meter;
+use super::profile::ProcessingTime;
+use super::resample::Resampler;
+use super::tap::TapWriter;
 use crate::route::Route;
 use generational_arena::Index;
 use sample::Sample;
+use std::sync::Arc;
+use std::time::Duration;
 
 use bufferpool::BufferPoolReference;
 
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// A named group of a node's input or output channels. The channels are
+/// split between the ports in order, so the first port has the first
+/// channels.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Port {
+    pub(crate) name: String,
+    pub(crate) channels: usize,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) sidechain: bool,
+}
+
+impl Port {
+    pub fn new(name: &str, channels: usize) -> Port {
+        Port {
+            name: name.to_string(),
+            channels,
+            sidechain: false,
+        }
+    }
+
+    /// An input port that's mixed separately from the node's other inputs
+    /// and passed to `Route::process_sidechain`. Its channels are in
+    /// addition to the node's input channels.
+    pub fn sidechain(name: &str, channels: usize) -> Port {
+        Port {
+            name: name.to_string(),
+            channels,
+            sidechain: true,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    pub fn is_sidechain(&self) -> bool {
+        self.sidechain
+    }
+}
+
+// The first channel of a port and its number of channels
+pub(crate) fn port_range(ports: &[Port], port: usize) -> Option<(usize, usize)> {
+    let channels = ports.get(port)?.channels;
+    let offset = ports[..port].iter().map(|port| port.channels).sum();
+
+    Some((offset, channels))
+}
+
+pub(crate) fn check_ports(ports: &[Port], channels: usize, inputs: bool) {
+    assert_eq!(
+        ports
+            .iter()
+            .filter(|port| !port.sidechain)
+            .map(|port| port.channels)
+            .sum::<usize>(),
+        channels,
+        "Ports must add up to the node's channels!"
+    );
+
+    // Sidechains are stored after the other inputs
+    let mut sidechains = ports.iter().skip_while(|port| !port.sidechain);
+
+    let valid = if inputs {
+        sidechains.all(|port| port.sidechain)
+    } else {
+        sidechains.next().is_none()
+    };
+
+    assert!(
+        valid,
+        "Sidechain ports must be inputs and come after the other ports!"
+    );
+}
+
+// The number of channels in the sidechain ports
+pub(crate) fn sidechain_channels(ports: &[Port]) -> usize {
+    ports
+        .iter()
+        .filter(|port| port.sidechain)
+        .map(|port| port.channels)
+        .sum()
+}
+
+fn find_port(ports: &[Port], name: &str) -> Option<usize> {
+    ports.iter().position(|port| port.name == name)
+}
+
 pub struct Connection<S> {
     pub(crate) id: Index,
     pub(crate) amount: S,
+    pub(crate) source_port: usize,
+    pub(crate) target_port: usize,
+    pub(crate) resampler: Option<Resampler>,
+    pub(crate) meter: Option<Meter>,
+    // The side of a topology crossfade the connection is on
+    pub(crate) side: Side,
 }
 
 impl<S: Sample> Connection<S> {
     pub fn new(id: Index, amount: S) -> Connection<S> {
-        Connection { id, amount }
+        Connection {
+            id,
+            amount,
+            source_port: 0,
+            target_port: 0,
+            resampler: None,
+            meter: None,
+            side: Side::Both,
+        }
+    }
+
+    /// Connect the source's output port `source` to the target's input
+    /// port `target`, instead of their first ports.
+    pub fn with_ports(mut self, source: usize, target: usize) -> Connection<S> {
+        self.source_port = source;
+        self.target_port = target;
+        self
     }
 
     pub fn id(&self) -> Index {
         self.id
     }
+
+    pub fn source_port(&self) -> usize {
+        self.source_port
+    }
+
+    pub fn target_port(&self) -> usize {
+        self.target_port
+    }
+
+    pub(crate) fn is_between(&self, target: Index, source_port: usize, target_port: usize) -> bool {
+        self.id == target && self.source_port == source_port && self.target_port == target_port
+    }
 }
 
 pub struct Node<S, R> {
     pub(crate) id: Index,
-    pub(crate) channels: usize,
+    pub(crate) label: Option<String>,
+    pub(crate) input_channels: usize,
+    pub(crate) output_channels: usize,
+    pub(crate) input_ports: Vec<Port>,
+    pub(crate) output_ports: Vec<Port>,
+    pub(crate) rate_divisor: usize,
     pub(crate) buffers: Vec<BufferPoolReference<S>>,
     pub(crate) connections: Vec<Connection<S>>,
+    // Connections from a topology that's being crossfaded out
+    pub(crate) fading_connections: Vec<Connection<S>>,
+    // The source of every connection to this node
+    pub(crate) incoming: Vec<Index>,
     pub(crate) route: R,
+    pub(crate) profile: Option<Arc<ProcessingTime>>,
+    // Time spent processing in the current block, recorded once it's done
+    pub(crate) elapsed: Option<Duration>,
+    pub(crate) meter: Option<Meter>,
+    pub(crate) tap: Option<TapWriter<S>>,
+    pub(crate) fade: Option<Fade>,
+    pub(crate) silent_frames: usize,
+    pub(crate) asleep: bool,
+    pub(crate) source: bool,
+    pub(crate) sink: bool,
+    pub(crate) pruned: bool,
+    // The latest signal arriving at the node, used to work out the
+    // graph's latency
+    pub(crate) input_latency: Option<usize>,
 }
 
 impl<S, R, C> Node<S, R>
@@ -36,10 +195,122 @@ where
         self.id
     }
 
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    pub fn set_label(&mut self, label: &str) {
+        self.label = Some(label.to_string());
+    }
+
+    pub fn input_channels(&self) -> usize {
+        self.input_channels
+    }
+
+    pub fn output_channels(&self) -> usize {
+        self.output_channels
+    }
+
+    /// Give the node a different number of output channels to input
+    /// channels, replacing any output ports.
+    pub fn with_output_channels(mut self, channels: usize) -> Self {
+        self.output_channels = channels;
+        self.output_ports = vec![Port::new("output", channels)];
+        self
+    }
+
+    /// Split the node's input channels into ports. Routes see the channels
+    /// of every input port one after another.
+    ///
+    /// # Panics
+    /// If the ports, other than sidechains, don't add up to the node's
+    /// input channels or a sidechain port comes before another kind of port
+    pub fn with_input_ports(mut self, ports: Vec<Port>) -> Self {
+        check_ports(&ports, self.input_channels, true);
+
+        self.input_ports = ports;
+        self.buffers.reserve_exact(self.input_buffers());
+        self
+    }
+
+    /// Split the node's output channels into ports. Routes write the
+    /// channels of every output port one after another.
+    ///
+    /// # Panics
+    /// If the ports don't add up to the node's output channels or any of
+    /// them is a sidechain
+    pub fn with_output_ports(mut self, ports: Vec<Port>) -> Self {
+        check_ports(&ports, self.output_channels, false);
+
+        self.output_ports = ports;
+        self
+    }
+
+    /// Add a sidechain input port called `name` with its own channels
+    ///
+    /// # Panics
+    /// If the node already has an input port called `name`
+    pub fn with_sidechain(mut self, name: &str, channels: usize) -> Self {
+        assert!(
+            find_port(&self.input_ports, name).is_none(),
+            "Input port names must be unique!"
+        );
+
+        self.input_ports.push(Port::sidechain(name, channels));
+        self.buffers.reserve_exact(self.input_buffers());
+        self
+    }
+
+    pub fn sidechain_channels(&self) -> usize {
+        sidechain_channels(&self.input_ports)
+    }
+
+    // The number of buffers the node's inputs are mixed into
+    pub(crate) fn input_buffers(&self) -> usize {
+        self.input_channels + self.sidechain_channels()
+    }
+
+    pub fn input_ports(&self) -> &[Port] {
+        &self.input_ports
+    }
+
+    pub fn output_ports(&self) -> &[Port] {
+        &self.output_ports
+    }
+
+    /// The position of the input port called `name`
+    pub fn input_port(&self, name: &str) -> Option<usize> {
+        find_port(&self.input_ports, name)
+    }
+
+    /// The position of the output port called `name`
+    pub fn output_port(&self, name: &str) -> Option<usize> {
+        find_port(&self.output_ports, name)
+    }
+
     pub fn route(&mut self) -> &mut R {
         &mut self.route
     }
 
+    /// Whether the node has stopped processing because its input has
+    /// been silent for longer than its route's tail
+    pub fn is_asleep(&self) -> bool {
+        self.asleep
+    }
+
+    /// Whether the node has finished fading out and is waiting to be removed
+    pub fn is_faded_out(&self) -> bool {
+        match &self.fade {
+            Some(fade) => !fade.is_fading_in() && fade.is_finished(),
+            None => false,
+        }
+    }
+
+    /// The node runs at the graph's sample rate divided by this
+    pub fn rate_divisor(&self) -> usize {
+        self.rate_divisor
+    }
+
     pub fn with_id(
         id: Index,
         channels: usize,
@@ -48,10 +319,28 @@ where
     ) -> Node<S, R> {
         Node {
             id,
-            channels,
+            label: None,
+            input_channels: channels,
+            output_channels: channels,
+            input_ports: vec![Port::new("input", channels)],
+            output_ports: vec![Port::new("output", channels)],
+            rate_divisor: 1,
             buffers: Vec::with_capacity(channels),
             route,
             connections,
+            fading_connections: vec![],
+            incoming: vec![],
+            profile: None,
+            elapsed: None,
+            meter: None,
+            tap: None,
+            fade: None,
+            silent_frames: 0,
+            asleep: false,
+            source: false,
+            sink: false,
+            pruned: false,
+            input_latency: None,
         }
     }
 }
@@ -0,0 +1,205 @@
+use super::node::check_ports;
+use super::{insert_with, Connection, Node, Port, RouteGraph};
+use crate::route::Route;
+use generational_arena::{Arena, Index};
+use sample::Sample;
+use std::collections::HashMap;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// A description of the nodes and connections in a graph that can be
+/// stored and used to rebuild the graph later.
+///
+/// Routes are described by `P`. Sub graphs can be stored by having `P`
+/// contain another `Patch`.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Patch<S, P> {
+    pub nodes: Vec<PatchNode<S, P>>,
+    pub inputs: Vec<Index>,
+    pub outputs: Vec<Index>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PatchNode<S, P> {
+    pub id: Index,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub label: Option<String>,
+    pub channels: usize,
+    /// Only set if the node has a different number of output channels
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub output_channels: Option<usize>,
+    /// Empty if the node only has the default ports
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub input_ports: Vec<Port>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub output_ports: Vec<Port>,
+    /// Only set if the node runs in a slower rate domain
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub rate_divisor: Option<usize>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub source: bool,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub sink: bool,
+    pub connections: Vec<PatchConnection<S>>,
+    pub route: P,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PatchConnection<S> {
+    pub id: Index,
+    pub amount: S,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub source_port: usize,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub target_port: usize,
+}
+
+impl<S, R, C> RouteGraph<S, R>
+where
+    S: Sample + Default,
+    R: Route<S, Context = C>,
+{
+    /// Describe the graph as a patch, using `describe` to describe each route.
+    pub fn to_patch<P, F: FnMut(&R) -> P>(&self, mut describe: F) -> Patch<S, P> {
+        let nodes = self
+            .arena
+            .iter()
+            .map(|(id, node)| PatchNode {
+                id,
+                label: node.label.clone(),
+                channels: node.input_channels,
+                output_channels: if node.output_channels != node.input_channels {
+                    Some(node.output_channels)
+                } else {
+                    None
+                },
+                input_ports: node.input_ports.clone(),
+                output_ports: node.output_ports.clone(),
+                rate_divisor: if node.rate_divisor != 1 {
+                    Some(node.rate_divisor)
+                } else {
+                    None
+                },
+                source: node.source,
+                sink: node.sink,
+                connections: node
+                    .connections
+                    .iter()
+                    .map(|connection| PatchConnection {
+                        id: connection.id,
+                        amount: connection.amount,
+                        source_port: connection.source_port,
+                        target_port: connection.target_port,
+                    })
+                    .collect(),
+                route: describe(&node.route),
+            })
+            .collect();
+
+        Patch {
+            nodes,
+            inputs: self.inputs.clone(),
+            outputs: self.outputs.clone(),
+        }
+    }
+
+    /// Build a sorted graph from a patch, using `create` to create each route.
+    /// The nodes will be given new indices.
+    ///
+    /// # Panics
+    /// If a node's ports aren't valid, see `Node::with_input_ports`, or
+    /// its rate divisor is zero
+    pub fn from_patch<P, F: FnMut(P) -> R>(
+        patch: Patch<S, P>,
+        buffer_size: usize,
+        mut create: F,
+    ) -> Self {
+        let mut arena = Arena::with_capacity(patch.nodes.len());
+        let mut ids: HashMap<Index, Index> = HashMap::with_capacity(patch.nodes.len());
+        let mut connections = Vec::with_capacity(patch.nodes.len());
+
+        for node in patch.nodes {
+            let route = create(node.route);
+            let channels = node.channels;
+            let output_channels = node.output_channels.unwrap_or(channels);
+            let (input_ports, output_ports) = (node.input_ports, node.output_ports);
+            let (label, rate_divisor) = (node.label, node.rate_divisor);
+            let (source, sink) = (node.source, node.sink);
+
+            assert!(
+                rate_divisor != Some(0),
+                "Rate divisor must be greater than zero!"
+            );
+
+            // Check the ports before the node is inserted
+            if !input_ports.is_empty() {
+                check_ports(&input_ports, channels, true);
+            }
+
+            if !output_ports.is_empty() {
+                check_ports(&output_ports, output_channels, false);
+            }
+
+            let id = insert_with(&mut arena, |id| {
+                let mut created = Node::with_id(id, channels, route, vec![])
+                    .with_output_channels(output_channels);
+
+                if !input_ports.is_empty() {
+                    created.input_ports = input_ports;
+                    created.buffers.reserve_exact(created.input_buffers());
+                }
+
+                if !output_ports.is_empty() {
+                    created.output_ports = output_ports;
+                }
+
+                created.label = label;
+                created.rate_divisor = rate_divisor.unwrap_or(1);
+                created.source = source;
+                created.sink = sink;
+                created
+            });
+
+            ids.insert(node.id, id);
+            connections.push((id, node.connections));
+        }
+
+        for (id, patch_connections) in connections {
+            if let Some(node) = arena.get_mut(id) {
+                node.connections = patch_connections
+                    .iter()
+                    .filter_map(|connection| {
+                        ids.get(&connection.id).map(|id| {
+                            Connection::new(*id, connection.amount)
+                                .with_ports(connection.source_port, connection.target_port)
+                        })
+                    })
+                    .collect();
+            }
+        }
+
+        let mut graph = RouteGraph::build(arena, buffer_size, 44_100.);
+
+        let inputs: Vec<Index> = patch
+            .inputs
+            .iter()
+            .filter_map(|id| ids.get(id).copied())
+            .collect();
+        let outputs: Vec<Index> = patch
+            .outputs
+            .iter()
+            .filter_map(|id| ids.get(id).copied())
+            .collect();
+
+        graph.set_inputs(&inputs);
+        graph.set_outputs(&outputs);
+        graph.update_resamplers();
+        graph.update_latency();
+
+        graph
+    }
+}
@@ -0,0 +1,155 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Processing times recorded on the audio thread. All of the values are
+/// atomics, so they can be read from another thread while the graph runs.
+pub struct ProcessingTime {
+    min: AtomicU64,
+    max: AtomicU64,
+    total: AtomicU64,
+    count: AtomicU64,
+    history: Box<[AtomicU64]>,
+    head: AtomicUsize,
+}
+
+impl ProcessingTime {
+    pub(crate) fn new(history: usize) -> ProcessingTime {
+        ProcessingTime {
+            min: AtomicU64::new(u64::MAX),
+            max: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+            history: (0..history).map(|_| AtomicU64::new(0)).collect(),
+            head: AtomicUsize::new(0),
+        }
+    }
+
+    pub(crate) fn record(&self, time: Duration) {
+        let nanos = time.as_nanos() as u64;
+
+        self.min.fetch_min(nanos, Ordering::Relaxed);
+        self.max.fetch_max(nanos, Ordering::Relaxed);
+        self.total.fetch_add(nanos, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+
+        if !self.history.is_empty() {
+            let head = self.head.load(Ordering::Relaxed);
+            self.history[head % self.history.len()].store(nanos, Ordering::Relaxed);
+            self.head.store(head.wrapping_add(1), Ordering::Release);
+        }
+    }
+
+    pub fn min(&self) -> Duration {
+        match self.min.load(Ordering::Relaxed) {
+            u64::MAX => Duration::from_nanos(0),
+            min => Duration::from_nanos(min),
+        }
+    }
+
+    pub fn max(&self) -> Duration {
+        Duration::from_nanos(self.max.load(Ordering::Relaxed))
+    }
+
+    pub fn average(&self) -> Duration {
+        let count = self.count.load(Ordering::Relaxed);
+        let total = self.total.load(Ordering::Relaxed);
+
+        Duration::from_nanos(total.checked_div(count).unwrap_or(0))
+    }
+
+    /// The number of blocks that have been recorded
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// The times of the most recent blocks, oldest first
+    pub fn recent(&self) -> Vec<Duration> {
+        let head = self.head.load(Ordering::Acquire);
+        let len = self.history.len().min(head);
+
+        (head - len..head)
+            .map(|i| {
+                Duration::from_nanos(self.history[i % self.history.len()].load(Ordering::Relaxed))
+            })
+            .collect()
+    }
+
+    pub fn reset(&self) {
+        self.min.store(u64::MAX, Ordering::Relaxed);
+        self.max.store(0, Ordering::Relaxed);
+        self.total.store(0, Ordering::Relaxed);
+        self.count.store(0, Ordering::Relaxed);
+
+        for time in self.history.iter() {
+            time.store(0, Ordering::Relaxed);
+        }
+
+        self.head.store(0, Ordering::Release);
+    }
+}
+
+/// Profiling information for the whole graph
+pub struct GraphProfile {
+    sample_rate: AtomicU64,
+    history: usize,
+    load: AtomicU64,
+    max_load: AtomicU64,
+    time: ProcessingTime,
+}
+
+impl GraphProfile {
+    pub(crate) fn new(sample_rate: f64, history: usize) -> GraphProfile {
+        GraphProfile {
+            sample_rate: AtomicU64::new(sample_rate.to_bits()),
+            history,
+            load: AtomicU64::new(0f64.to_bits()),
+            max_load: AtomicU64::new(0f64.to_bits()),
+            time: ProcessingTime::new(history),
+        }
+    }
+
+    pub(crate) fn set_sample_rate(&self, sample_rate: f64) {
+        self.sample_rate
+            .store(sample_rate.to_bits(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn history(&self) -> usize {
+        self.history
+    }
+
+    pub(crate) fn record(&self, time: Duration, frames: usize) {
+        self.time.record(time);
+
+        let sample_rate = f64::from_bits(self.sample_rate.load(Ordering::Relaxed));
+
+        if frames > 0 && sample_rate > 0. {
+            let load = time.as_secs_f64() * sample_rate / frames as f64;
+
+            self.load.store(load.to_bits(), Ordering::Relaxed);
+
+            if load > self.max_load() {
+                self.max_load.store(load.to_bits(), Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// The time taken to process the last block as a fraction of the
+    /// block's real time duration. Anything above 1 is too slow.
+    pub fn load(&self) -> f64 {
+        f64::from_bits(self.load.load(Ordering::Relaxed))
+    }
+
+    pub fn max_load(&self) -> f64 {
+        f64::from_bits(self.max_load.load(Ordering::Relaxed))
+    }
+
+    /// The time taken to process the whole graph
+    pub fn time(&self) -> &ProcessingTime {
+        &self.time
+    }
+
+    pub fn reset(&self) {
+        self.time.reset();
+        self.max_load.store(0f64.to_bits(), Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,166 @@
+use super::{Node, RouteGraph};
+use crate::route::Route;
+use generational_arena::Index;
+use sample::Sample;
+use std::collections::HashSet;
+
+impl<S, R, C> RouteGraph<S, R>
+where
+    S: Sample + Default,
+    R: Route<S, Context = C>,
+{
+    // Nodes where signal starts: the graph's inputs and any node marked
+    // as a source.
+    fn is_source(&self, id: Index, node: &Node<S, R>) -> bool {
+        self.inputs.contains(&id) || node.source
+    }
+
+    // Nodes where signal ends up: the graph's outputs and any node marked
+    // as a sink.
+    fn is_sink(&self, id: Index, node: &Node<S, R>) -> bool {
+        self.outputs.contains(&id) || node.sink
+    }
+
+    // Every node that can be reached from `starts` by following `next`
+    fn search<'a, F, I>(&'a self, starts: Vec<Index>, next: F) -> HashSet<Index>
+    where
+        F: Fn(&'a Node<S, R>) -> I,
+        I: Iterator<Item = Index>,
+    {
+        let mut found: HashSet<Index> = starts.iter().copied().collect();
+        let mut stack = starts;
+
+        while let Some(id) = stack.pop() {
+            if let Some(node) = self.arena.get(id) {
+                for neighbour in next(node) {
+                    if found.insert(neighbour) {
+                        stack.push(neighbour);
+                    }
+                }
+            }
+        }
+
+        found
+    }
+
+    /// Nodes that no signal reaches, because they aren't connected to the
+    /// graph's inputs or to a source
+    pub fn unreachable_nodes(&self) -> Vec<Index> {
+        let sources = self
+            .arena
+            .iter()
+            .filter(|(id, node)| self.is_source(*id, node))
+            .map(|(id, _)| id)
+            .collect();
+
+        let reached = self.search(sources, |node| {
+            node.connections.iter().map(|connection| connection.id)
+        });
+
+        self.arena
+            .iter()
+            .map(|(id, _)| id)
+            .filter(|id| !reached.contains(id))
+            .collect()
+    }
+
+    /// Nodes whose output never reaches the graph's outputs or a sink
+    pub fn dead_nodes(&self) -> Vec<Index> {
+        let sinks = self
+            .arena
+            .iter()
+            .filter(|(id, node)| self.is_sink(*id, node))
+            .map(|(id, _)| id)
+            .collect();
+
+        let live = self.search(sinks, |node| node.incoming.iter().copied());
+
+        self.arena
+            .iter()
+            .map(|(id, _)| id)
+            .filter(|id| !live.contains(id))
+            .collect()
+    }
+
+    /// Mark a node as somewhere signal starts, like a node that makes sound
+    /// without any input, so the nodes it feeds aren't treated as unreachable
+    pub fn set_source(&mut self, id: Index, source: bool) {
+        if let Some(node) = self.arena.get_mut(id) {
+            node.source = source;
+        }
+
+        self.update_pruning();
+    }
+
+    /// Mark a node as somewhere signal ends up, like a node that records
+    /// its input, so it isn't treated as dead
+    pub fn set_sink(&mut self, id: Index, sink: bool) {
+        if let Some(node) = self.arena.get_mut(id) {
+            node.sink = sink;
+        }
+
+        self.update_pruning();
+    }
+
+    /// Skip unreachable and dead nodes while processing. They stay in the
+    /// graph and start processing again once they're connected.
+    pub fn set_pruning(&mut self, pruning: bool) {
+        self.pruning = pruning;
+        self.update_pruning();
+    }
+
+    /// Whether a node is being skipped because it's unreachable or dead
+    pub fn is_pruned(&self, id: Index) -> bool {
+        self.arena.get(id).map(|node| node.pruned).unwrap_or(false)
+    }
+
+    // Work out which nodes to skip. The passes follow the ordering, so
+    // they don't allocate and can run when a topology is swapped in.
+    pub(crate) fn update_pruning(&mut self) {
+        if !self.pruning || !self.sorted {
+            for (_, node) in self.arena.iter_mut() {
+                node.pruned = false;
+            }
+
+            return;
+        }
+
+        // Nodes that signal reaches, marked as not pruned
+        for i in 0..self.ordering.len() {
+            let id = self.ordering[i];
+
+            if let Some(node) = self.arena.get(id) {
+                let reached = self.is_source(id, node)
+                    || node.incoming.iter().any(|source| {
+                        self.arena
+                            .get(*source)
+                            .map(|source| !source.pruned)
+                            .unwrap_or(false)
+                    });
+
+                self.arena[id].pruned = !reached;
+            }
+        }
+
+        // Of those, the nodes that reach a sink. Everything a reached node
+        // sends to is also reached, so its targets are only pruned if
+        // they're dead.
+        for i in (0..self.ordering.len()).rev() {
+            let id = self.ordering[i];
+
+            if let Some(node) = self.arena.get(id) {
+                let live = self.is_sink(id, node)
+                    || node.connections.iter().any(|connection| {
+                        self.arena
+                            .get(connection.id)
+                            .map(|target| !target.pruned)
+                            .unwrap_or(false)
+                    });
+
+                if !live {
+                    self.arena[id].pruned = true;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use super::{Node, RouteGraph};
+use crate::route::Route;
+use generational_arena::Index;
+use sample::Sample;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+impl<S, R, C> RouteGraph<S, R>
+where
+    S: Sample + Default,
+    R: Route<S, Context = C>,
+{
+    /// Every node in the graph, in no particular order
+    pub fn nodes(&self) -> impl Iterator<Item = (Index, &Node<S, R>)> {
+        self.arena.iter()
+    }
+
+    /// Every connection in the graph as its source, target, amount, source
+    /// port and target port
+    pub fn edges(&self) -> impl Iterator<Item = (Index, Index, S, usize, usize)> + '_ {
+        self.arena.iter().flat_map(|(id, node)| {
+            node.connections.iter().map(move |connection| {
+                (
+                    id,
+                    connection.id,
+                    connection.amount,
+                    connection.source_port,
+                    connection.target_port,
+                )
+            })
+        })
+    }
+
+    /// The order the nodes are processed in. This is only up to date when
+    /// the graph is sorted.
+    pub fn ordering(&self) -> &[Index] {
+        &self.ordering
+    }
+
+    // Every node that can be reached from `id` by following `next`
+    fn reachable<'a, F, I>(&'a self, id: Index, next: F) -> Vec<Index>
+    where
+        F: Fn(&'a Node<S, R>) -> I,
+        I: Iterator<Item = Index>,
+    {
+        let mut found = vec![];
+        let mut visited = HashSet::new();
+        let mut queue: VecDeque<Index> = VecDeque::new();
+
+        visited.insert(id);
+        queue.push_back(id);
+
+        while let Some(current) = queue.pop_front() {
+            if let Some(node) = self.arena.get(current) {
+                for neighbour in next(node) {
+                    if visited.insert(neighbour) && self.arena.contains(neighbour) {
+                        found.push(neighbour);
+                        queue.push_back(neighbour);
+                    }
+                }
+            }
+        }
+
+        found
+    }
+
+    /// Every node that sends signal to `id`, directly or through other nodes
+    pub fn upstream(&self, id: Index) -> Vec<Index> {
+        self.reachable(id, |node| node.incoming.iter().copied())
+    }
+
+    /// Every node that `id` sends signal to, directly or through other nodes
+    pub fn downstream(&self, id: Index) -> Vec<Index> {
+        self.reachable(id, |node| {
+            node.connections.iter().map(|connection| connection.id)
+        })
+    }
+
+    /// The shortest chain of connections from `source` to `target`,
+    /// including both of them
+    pub fn path(&self, source: Index, target: Index) -> Option<Vec<Index>> {
+        if !self.arena.contains(source) || !self.arena.contains(target) {
+            return None;
+        }
+
+        let mut previous: HashMap<Index, Index> = HashMap::new();
+        let mut queue: VecDeque<Index> = VecDeque::new();
+
+        queue.push_back(source);
+
+        while let Some(current) = queue.pop_front() {
+            if current == target {
+                let mut path = vec![target];
+
+                while let Some(node) = previous.get(path.last().unwrap()) {
+                    path.push(*node);
+                }
+
+                path.reverse();
+
+                return Some(path);
+            }
+
+            if let Some(node) = self.arena.get(current) {
+                for connection in node.connections.iter() {
+                    if connection.id != source && !previous.contains_key(&connection.id) {
+                        previous.insert(connection.id, current);
+                        queue.push_back(connection.id);
+                    }
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Whether signal from `source` reaches `target`
+    pub fn is_reachable(&self, source: Index, target: Index) -> bool {
+        self.path(source, target).is_some()
+    }
+}
@@ -0,0 +1,142 @@
+use crate::convert::{from_f64, to_f64};
+use sample::Sample;
+
+// Number of zero crossings on each side of the resampling filter
+const ZERO_CROSSINGS: usize = 8;
+
+// The first frame of a block starting at `clock` that a node running at
+// `1 / divisor` of the graph rate processes
+pub(crate) fn first_frame(divisor: usize, clock: usize) -> usize {
+    (divisor - clock % divisor) % divisor
+}
+
+// The number of frames a node running at `1 / divisor` of the graph rate
+// produces in a block of `frames` frames starting at `clock`.
+pub(crate) fn domain_frames(divisor: usize, clock: usize, frames: usize) -> usize {
+    let first = first_frame(divisor, clock);
+
+    if first < frames {
+        (frames - first - 1) / divisor + 1
+    } else {
+        0
+    }
+}
+
+// The node's frame that's heard at `frame` of the block. Each frame is held
+// until the node's next one, and frames before the first are given it early.
+pub(crate) fn held_frame(divisor: usize, clock: usize, frame: usize) -> usize {
+    frame.saturating_sub(first_frame(divisor, clock)) / divisor
+}
+
+/// Resamples a connection between nodes in different rate domains.
+///
+/// The signal is brought up to the graph's rate, filtered with a windowed
+/// sinc lowpass below the nyquist of the slower domain, and then picked at
+/// the rate of the receiving domain.
+pub(crate) struct Resampler {
+    from: usize,
+    to: usize,
+    taps: Vec<f64>,
+    history: Vec<Vec<f64>>,
+    position: usize,
+}
+
+impl Resampler {
+    pub(crate) fn new(from: usize, to: usize, channels: usize) -> Resampler {
+        let slowest = from.max(to);
+        let len = 2 * ZERO_CROSSINGS * slowest + 1;
+        let center = (len / 2) as f64;
+        let cutoff = 0.45 / slowest as f64;
+
+        let mut taps: Vec<f64> = (0..len)
+            .map(|n| {
+                let x = n as f64 - center;
+                let sinc = if x == 0. {
+                    2. * cutoff
+                } else {
+                    (2. * std::f64::consts::PI * cutoff * x).sin() / (std::f64::consts::PI * x)
+                };
+                let phase = 2. * std::f64::consts::PI * n as f64 / (len - 1) as f64;
+                let window = 0.42 - 0.5 * phase.cos() + 0.08 * (2. * phase).cos();
+                sinc * window
+            })
+            .collect();
+
+        let sum: f64 = taps.iter().sum();
+
+        for tap in taps.iter_mut() {
+            *tap /= sum;
+        }
+
+        Resampler {
+            from,
+            to,
+            history: vec![vec![0.; len]; channels],
+            taps,
+            position: 0,
+        }
+    }
+
+    pub(crate) fn matches(&self, from: usize, to: usize, channels: usize) -> bool {
+        self.from == from && self.to == to && self.history.len() == channels
+    }
+
+    /// The delay of the filter in frames at the graph's rate
+    pub(crate) fn latency(&self) -> usize {
+        self.taps.len() / 2
+    }
+
+    /// Resample and mix `input` into `output` for a block of `frames` frames
+    /// at the graph's rate. Every channel needs to be processed each block.
+    pub(crate) fn process<S: Sample>(
+        &mut self,
+        channel: usize,
+        clock: usize,
+        frames: usize,
+        input: &[S],
+        output: &mut [S],
+        amount: S,
+    ) {
+        let history = match self.history.get_mut(channel) {
+            Some(history) => history,
+            None => return,
+        };
+
+        let len = history.len();
+        let gain = self.from as f64;
+        let amount = to_f64(amount);
+
+        let mut position = self.position;
+        let mut inputs = input.iter();
+        let mut outputs = output.iter_mut();
+
+        for tick in clock..(clock + frames) {
+            position = (position + len - 1) % len;
+
+            history[position] = if tick % self.from == 0 {
+                inputs
+                    .next()
+                    .map(|sample| to_f64(*sample) * gain)
+                    .unwrap_or(0.)
+            } else {
+                0.
+            };
+
+            if tick % self.to == 0 {
+                if let Some(output) = outputs.next() {
+                    let mut value = 0.;
+
+                    for (i, tap) in self.taps.iter().enumerate() {
+                        value += tap * history[(position + i) % len];
+                    }
+
+                    *output = output.add_amp(from_f64::<S>(value * amount).to_signed_sample());
+                }
+            }
+        }
+
+        if channel + 1 == self.history.len() {
+            self.position = position;
+        }
+    }
+}
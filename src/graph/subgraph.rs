@@ -0,0 +1,170 @@
+use super::{Patch, RouteGraph};
+use crate::route::Route;
+use bufferpool::BufferPoolReference;
+use generational_arena::Index;
+use sample::Sample;
+
+/// A route that processes a whole `RouteGraph`, so that groups of nodes
+/// can be reused as a single node inside of another graph.
+///
+/// The sub graph's input is mixed into its `input` node and the output
+/// of its `output` node becomes the output of the route. A sidechain is
+/// mixed into the `sidechain` node, if there is one.
+pub struct SubGraph<S: Sample + Default, R> {
+    graph: RouteGraph<S, R>,
+    input: Index,
+    output: Index,
+    sidechain: Option<Index>,
+}
+
+impl<S, R, C> SubGraph<S, R>
+where
+    S: Sample + Default,
+    R: Route<S, Context = C>,
+{
+    pub fn new(mut graph: RouteGraph<S, R>, input: Index, output: Index) -> Self {
+        graph.set_inputs(&[input]);
+        graph.set_outputs(&[output]);
+
+        SubGraph {
+            graph,
+            input,
+            output,
+            sidechain: None,
+        }
+    }
+
+    /// Mix the sidechain into the `sidechain` node of the sub graph
+    pub fn with_sidechain(mut self, sidechain: Index) -> Self {
+        self.graph.set_inputs(&[self.input, sidechain]);
+        self.sidechain = Some(sidechain);
+        self
+    }
+
+    /// Create a sub graph from a patch, using the patch's first input
+    /// and output nodes.
+    ///
+    /// # Panics
+    /// If the patch doesn't have an input and an output node
+    pub fn from_patch<P, F: FnMut(P) -> R>(
+        patch: Patch<S, P>,
+        buffer_size: usize,
+        create: F,
+    ) -> Self {
+        let graph = RouteGraph::from_patch(patch, buffer_size, create);
+
+        let input = *graph
+            .inputs
+            .first()
+            .expect("Patch for a sub graph needs an input node!");
+        let output = *graph
+            .outputs
+            .first()
+            .expect("Patch for a sub graph needs an output node!");
+
+        SubGraph {
+            graph,
+            input,
+            output,
+            sidechain: None,
+        }
+    }
+
+    pub fn to_patch<P, F: FnMut(&R) -> P>(&self, describe: F) -> Patch<S, P> {
+        self.graph.to_patch(describe)
+    }
+
+    pub fn input(&self) -> Index {
+        self.input
+    }
+
+    pub fn output(&self) -> Index {
+        self.output
+    }
+
+    pub fn sidechain(&self) -> Option<Index> {
+        self.sidechain
+    }
+
+    pub fn graph(&self) -> &RouteGraph<S, R> {
+        &self.graph
+    }
+
+    pub fn graph_mut(&mut self) -> &mut RouteGraph<S, R> {
+        &mut self.graph
+    }
+
+    pub fn into_inner(self) -> RouteGraph<S, R> {
+        self.graph
+    }
+}
+
+impl<S, R, C> Route<S> for SubGraph<S, R>
+where
+    S: Sample + Default,
+    R: Route<S, Context = C>,
+{
+    type Context = C;
+
+    fn process(
+        &mut self,
+        input: &[BufferPoolReference<S>],
+        output: &mut [BufferPoolReference<S>],
+        frames: usize,
+        context: &mut Self::Context,
+    ) {
+        self.graph
+            .process_with_buffers(input, output, frames, context);
+    }
+
+    fn process_sidechain(
+        &mut self,
+        input: &[BufferPoolReference<S>],
+        sidechain: &[BufferPoolReference<S>],
+        output: &mut [BufferPoolReference<S>],
+        frames: usize,
+        context: &mut Self::Context,
+    ) {
+        // The sidechain channels follow on from the input node's channels,
+        // so they can only be lined up when all of those are there
+        let channels = self
+            .graph
+            .arena
+            .get(self.input)
+            .map_or(0, |node| node.input_channels);
+
+        if self.sidechain.is_some() && input.len() >= channels {
+            self.graph.process_with_sidechain(
+                &input[..channels],
+                sidechain,
+                output,
+                frames,
+                context,
+            );
+        } else {
+            self.graph
+                .process_with_buffers(input, output, frames, context);
+        }
+    }
+
+    fn latency(&self) -> usize {
+        self.graph.latency()
+    }
+
+    fn prepare(&mut self, sample_rate: f64, max_block: usize, _channels: usize) {
+        // Resize the pool first so the inner routes are only prepared once
+        if max_block != self.graph.buffer_size() {
+            self.graph.pool.change_buffer_size(max_block);
+        }
+
+        self.graph.set_sample_rate(sample_rate);
+    }
+
+    fn reset(&mut self) {
+        self.graph.silence_all_buffers();
+    }
+
+    fn release(&mut self) {
+        self.graph.release_all();
+    }
+}
@@ -0,0 +1,212 @@
+use bufferpool::BufferPoolReference;
+use sample::Sample;
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+// Frames are stored interleaved. `write` and `read` count frames and only
+// ever increase, so the number of frames in the buffer is their difference.
+struct Ring<S> {
+    buffer: Box<[UnsafeCell<S>]>,
+    channels: usize,
+    capacity: usize,
+    write: AtomicUsize,
+    read: AtomicUsize,
+    overflows: AtomicUsize,
+}
+
+// There's only ever one writer and one reader, and they never touch the
+// same frames at the same time.
+unsafe impl<S: Send> Send for Ring<S> {}
+unsafe impl<S: Send> Sync for Ring<S> {}
+
+impl<S: Sample> Ring<S> {
+    fn slot(&self, frame: usize, channel: usize) -> *mut S {
+        self.buffer[(frame % self.capacity) * self.channels + channel].get()
+    }
+}
+
+/// Writes a node's output into a tap's ring buffer on the audio thread
+pub(crate) struct TapWriter<S> {
+    ring: Arc<Ring<S>>,
+}
+
+impl<S: Sample> TapWriter<S> {
+    pub(crate) fn write(&mut self, buffers: &[BufferPoolReference<S>], frames: usize) {
+        let ring = &self.ring;
+
+        let write = ring.write.load(Ordering::Relaxed);
+        let read = ring.read.load(Ordering::Acquire);
+
+        let space = ring.capacity - write.wrapping_sub(read);
+        let written = frames.min(space);
+
+        for channel in 0..ring.channels {
+            let buffer = buffers.get(channel).map(|buffer| buffer.as_ref());
+
+            for frame in 0..written {
+                let sample = buffer
+                    .and_then(|buffer| buffer.get(frame))
+                    .copied()
+                    .unwrap_or_else(S::equilibrium);
+
+                unsafe {
+                    *ring.slot(write.wrapping_add(frame), channel) = sample;
+                }
+            }
+        }
+
+        ring.write
+            .store(write.wrapping_add(written), Ordering::Release);
+
+        if written < frames {
+            ring.overflows
+                .fetch_add(frames - written, Ordering::Relaxed);
+        }
+    }
+}
+
+/// The reading end of a tap on a node's output. Frames that don't fit in
+/// the ring buffer are dropped and counted as overflows.
+pub struct Tap<S> {
+    ring: Arc<Ring<S>>,
+}
+
+impl<S: Sample> Tap<S> {
+    pub(crate) fn new(channels: usize, capacity: usize) -> (Tap<S>, TapWriter<S>) {
+        let ring = Arc::new(Ring {
+            buffer: (0..channels * capacity)
+                .map(|_| UnsafeCell::new(S::equilibrium()))
+                .collect(),
+            channels,
+            capacity,
+            write: AtomicUsize::new(0),
+            read: AtomicUsize::new(0),
+            overflows: AtomicUsize::new(0),
+        });
+
+        (
+            Tap {
+                ring: Arc::clone(&ring),
+            },
+            TapWriter { ring },
+        )
+    }
+
+    pub fn channels(&self) -> usize {
+        self.ring.channels
+    }
+
+    /// The number of frames the ring buffer can hold
+    pub fn capacity(&self) -> usize {
+        self.ring.capacity
+    }
+
+    /// The number of frames waiting to be read
+    pub fn available(&self) -> usize {
+        let write = self.ring.write.load(Ordering::Acquire);
+        write.wrapping_sub(self.ring.read.load(Ordering::Relaxed))
+    }
+
+    /// The number of frames that have been dropped because the buffer was full
+    pub fn overflows(&self) -> usize {
+        self.ring.overflows.load(Ordering::Relaxed)
+    }
+
+    /// Read interleaved frames into `output`, returning the number of frames read
+    pub fn read(&mut self, output: &mut [S]) -> usize {
+        let channels = self.ring.channels.max(1);
+        let frames = self.available().min(output.len() / channels);
+        let read = self.ring.read.load(Ordering::Relaxed);
+
+        for (frame, output) in output.chunks_mut(channels).take(frames).enumerate() {
+            for (channel, output) in output.iter_mut().enumerate() {
+                *output = unsafe { *self.ring.slot(read.wrapping_add(frame), channel) };
+            }
+        }
+
+        self.ring
+            .read
+            .store(read.wrapping_add(frames), Ordering::Release);
+
+        frames
+    }
+}
+
+#[cfg(feature = "hound")]
+impl<S: Sample + hound::Sample> Tap<S> {
+    /// Write every available frame to `writer`, returning the number of
+    /// frames written. The writer should have as many channels as the tap.
+    pub fn drain_to_wav<W>(&mut self, writer: &mut hound::WavWriter<W>) -> hound::Result<usize>
+    where
+        W: std::io::Write + std::io::Seek,
+    {
+        let mut buffer = vec![S::equilibrium(); 1024 * self.channels().max(1)];
+        let mut total = 0;
+
+        loop {
+            let frames = self.read(&mut buffer);
+
+            if frames == 0 {
+                return Ok(total);
+            }
+
+            for sample in buffer[..frames * self.channels()].iter() {
+                writer.write_sample(*sample)?;
+            }
+
+            total += frames;
+        }
+    }
+}
+
+#[cfg(all(test, feature = "hound"))]
+mod tests {
+    use super::*;
+    use bufferpool::{BufferPool, BufferPoolBuilder};
+
+    #[test]
+    fn test_drain_tap_to_wav() {
+        let mut pool: BufferPool<f32> = BufferPoolBuilder::new()
+            .with_capacity(2)
+            .with_buffer_size(16)
+            .build();
+
+        let mut left = pool.get_cleared_space().unwrap();
+        let mut right = pool.get_cleared_space().unwrap();
+
+        for (i, (l, r)) in left.as_mut().iter_mut().zip(right.as_mut()).enumerate() {
+            *l = i as f32 / 16.;
+            *r = -(i as f32) / 16.;
+        }
+
+        let (mut tap, mut writer) = Tap::new(2, 16);
+        writer.write(&[left, right], 16);
+
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 44_100,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+
+        let mut cursor = std::io::Cursor::new(Vec::new());
+        let mut wav = hound::WavWriter::new(&mut cursor, spec).unwrap();
+
+        assert_eq!(tap.drain_to_wav(&mut wav).unwrap(), 16);
+        assert_eq!(tap.available(), 0);
+
+        wav.finalize().unwrap();
+        cursor.set_position(0);
+
+        let samples: Vec<f32> = hound::WavReader::new(cursor)
+            .unwrap()
+            .into_samples()
+            .map(|s| s.unwrap())
+            .collect();
+
+        assert_eq!(samples.len(), 32);
+        assert_eq!(samples[6], 3. / 16.);
+        assert_eq!(samples[7], -3. / 16.);
+    }
+}
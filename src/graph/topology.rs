@@ -0,0 +1,685 @@
+use super::fade::{Fade, Side};
+use super::node::{port_range, sidechain_channels};
+use super::resample::Resampler;
+use super::{sort_positions, Connection, Node, Port, RouteGraph};
+use crate::route::Route;
+use bufferpool::{BufferPool, BufferPoolBuilder};
+use generational_arena::Index;
+use sample::Sample;
+use std::collections::HashMap;
+use std::fmt;
+
+/// A topology's connections contain a cycle through this node
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CycleError(pub Index);
+
+impl fmt::Display for CycleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "cycle through node {:?}", self.0)
+    }
+}
+
+impl std::error::Error for CycleError {}
+
+struct TopologyNode<S> {
+    id: Index,
+    input_channels: usize,
+    output_channels: usize,
+    input_ports: Vec<Port>,
+    output_ports: Vec<Port>,
+    rate_divisor: usize,
+    connections: Vec<Connection<S>>,
+    // The connections the graph had when the topology was taken
+    previous: Vec<Connection<S>>,
+}
+
+// The nodes a topology swaps connections into
+struct SwapNode<S> {
+    id: Index,
+    connections: Vec<Connection<S>>,
+    incoming: Vec<Index>,
+    // The old connections, played while the topology is crossfaded in
+    fading: Vec<Connection<S>>,
+}
+
+fn copy_connection<S: Sample>(connection: &Connection<S>) -> Connection<S> {
+    Connection::new(connection.id, connection.amount)
+        .with_ports(connection.source_port, connection.target_port)
+}
+
+// Combine the sides of the signals arriving at a node. A node that hears
+// both topologies, or a mix of them, is on both sides.
+fn merge_side(current: Option<Side>, side: Side) -> Option<Side> {
+    match current {
+        Some(current) if current != side => Some(Side::Both),
+        _ => Some(side),
+    }
+}
+
+/// A copy of a graph's connections that can be edited away from the audio
+/// thread. Once it's built it can be swapped into the graph in one go.
+pub struct TopologyBuilder<S> {
+    nodes: Vec<TopologyNode<S>>,
+    removed: Vec<TopologyNode<S>>,
+    inputs: Vec<Index>,
+    outputs: Vec<Index>,
+    previous_inputs: Vec<Index>,
+    previous_outputs: Vec<Index>,
+    buffer_size: usize,
+    crossfade: usize,
+}
+
+impl<S: Sample + Default> TopologyBuilder<S> {
+    fn node_mut(&mut self, id: Index) -> Option<&mut TopologyNode<S>> {
+        self.nodes.iter_mut().find(|node| node.id == id)
+    }
+
+    fn contains(&self, id: Index) -> bool {
+        self.nodes.iter().any(|node| node.id == id)
+    }
+
+    /// Crossfade from the old connections to the new ones over `frames`
+    /// frames. Both are heard while it fades, mixed with complementary
+    /// gains, and removed nodes keep playing until it's finished.
+    ///
+    /// Each node is still only processed once, so a node that both sets of
+    /// connections send to hears a mix of the two.
+    pub fn with_crossfade(mut self, frames: usize) -> Self {
+        self.crossfade = frames;
+        self
+    }
+
+    /// Connect the first ports of `source` and `target`, or change the
+    /// amount if they're already connected.
+    pub fn connect(&mut self, source: Index, target: Index, amount: S) {
+        if !self.contains(target) {
+            return;
+        }
+
+        if let Some(node) = self.node_mut(source) {
+            match node
+                .connections
+                .iter_mut()
+                .find(|c| c.is_between(target, 0, 0))
+            {
+                Some(connection) => connection.amount = amount,
+                None => node.connections.push(Connection::new(target, amount)),
+            }
+        }
+    }
+
+    pub fn disconnect(&mut self, source: Index, target: Index) {
+        if let Some(node) = self.node_mut(source) {
+            node.connections
+                .retain(|connection| connection.id != target);
+        }
+    }
+
+    /// Remove every connection from `source`
+    pub fn disconnect_all(&mut self, source: Index) {
+        if let Some(node) = self.node_mut(source) {
+            node.connections.clear();
+        }
+    }
+
+    /// Remove a node from the graph when the topology is swapped in
+    pub fn remove_node(&mut self, id: Index) {
+        let position = match self.nodes.iter().position(|node| node.id == id) {
+            Some(position) => position,
+            None => return,
+        };
+
+        let mut node = self.nodes.remove(position);
+        node.connections.clear();
+
+        for node in self.nodes.iter_mut() {
+            node.connections.retain(|connection| connection.id != id);
+        }
+
+        self.inputs.retain(|input| input != &id);
+        self.outputs.retain(|output| output != &id);
+        self.removed.push(node);
+    }
+
+    pub fn set_inputs(&mut self, inputs: &[Index]) {
+        self.inputs.clear();
+        self.inputs.extend_from_slice(inputs);
+    }
+
+    pub fn set_outputs(&mut self, outputs: &[Index]) {
+        self.outputs.clear();
+        self.outputs.extend_from_slice(outputs);
+    }
+
+    /// Sort the nodes, create any resamplers and allocate the buffers that
+    /// the new topology needs. Fails if the connections contain a cycle.
+    pub fn build<R>(mut self) -> Result<Topology<S, R>, CycleError> {
+        let crossfade = self.crossfade > 0;
+
+        // The old connections and terminals only matter while there's a crossfade
+        if !crossfade {
+            for node in self.nodes.iter_mut().chain(self.removed.iter_mut()) {
+                node.previous.clear();
+            }
+
+            self.previous_inputs.clear();
+            self.previous_outputs.clear();
+        }
+
+        let removed: Vec<Index> = self.removed.iter().map(|node| node.id).collect();
+
+        // Removed nodes go at the end, and are only processed while the
+        // topology is crossfaded in
+        let live = self.nodes.len();
+        let mut nodes = self.nodes;
+        nodes.extend(self.removed);
+
+        let positions: HashMap<Index, usize> = nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| (node.id, i))
+            .collect();
+
+        let targets = |connections: &[Connection<S>]| -> Vec<usize> {
+            connections
+                .iter()
+                .filter_map(|connection| positions.get(&connection.id).copied())
+                .collect()
+        };
+
+        let edges: Vec<Vec<usize>> = nodes
+            .iter()
+            .map(|node| targets(&node.connections))
+            .collect();
+
+        let sorted = sort_positions(&edges).map_err(|i| CycleError(nodes[i].id))?;
+
+        let ordering: Vec<Index> = sorted
+            .iter()
+            .filter(|i| **i < live)
+            .map(|i| nodes[*i].id)
+            .collect();
+
+        // While fading, the old and new connections are processed together.
+        // If that's a cycle the old connections that go against the new
+        // order are dropped.
+        let fade_edges = |nodes: &[TopologyNode<S>]| -> Vec<Vec<usize>> {
+            nodes
+                .iter()
+                .map(|node| {
+                    let mut edges = targets(&node.connections);
+                    edges.extend(targets(&node.previous));
+                    edges
+                })
+                .collect()
+        };
+
+        let fade_sorted = sort_positions(&fade_edges(&nodes)).unwrap_or_else(|_| sorted.clone());
+
+        let mut rank = vec![0; nodes.len()];
+
+        for (i, position) in fade_sorted.iter().enumerate() {
+            rank[*position] = i;
+        }
+
+        for (i, node) in nodes.iter_mut().enumerate() {
+            node.previous.retain(|connection| {
+                positions
+                    .get(&connection.id)
+                    .map(|target| rank[*target] > rank[i])
+                    .unwrap_or(false)
+            });
+        }
+
+        let fade_edges = fade_edges(&nodes);
+
+        let fade_ordering: Vec<Index> = fade_sorted.iter().map(|i| nodes[*i].id).collect();
+
+        // Work out which topology the signal leaving each node belongs to.
+        // Connections that are in both are faded out and in at once, and a
+        // signal that's already been faded isn't faded again.
+        let mut arriving: Vec<Option<Side>> = vec![None; nodes.len()];
+
+        if crossfade {
+            for (inputs, side) in [
+                (&self.inputs, Side::New),
+                (&self.previous_inputs, Side::Old),
+            ]
+            .iter()
+            {
+                for id in inputs.iter() {
+                    if let Some(i) = positions.get(id) {
+                        arriving[*i] = merge_side(arriving[*i], *side);
+                    }
+                }
+            }
+        }
+
+        let mut sides = vec![Side::Both; nodes.len()];
+
+        for position in fade_sorted.iter() {
+            let side = arriving[*position].unwrap_or(Side::Both);
+            sides[*position] = side;
+
+            let node = &mut nodes[*position];
+
+            for connection in node.connections.iter_mut() {
+                let shared = node.previous.iter().any(|previous| {
+                    previous.is_between(
+                        connection.id,
+                        connection.source_port,
+                        connection.target_port,
+                    )
+                });
+
+                let (gain, heard) = match side {
+                    _ if shared => (Side::New, side),
+                    Side::New => (Side::Both, Side::New),
+                    Side::Both => (Side::New, Side::New),
+                    Side::Old => (Side::New, Side::Both),
+                };
+
+                if crossfade {
+                    connection.side = gain;
+                }
+
+                if let Some(target) = positions.get(&connection.id) {
+                    arriving[*target] = merge_side(arriving[*target], heard);
+                }
+            }
+
+            for previous in node.previous.iter_mut() {
+                let shared = node.connections.iter().any(|connection| {
+                    connection.is_between(previous.id, previous.source_port, previous.target_port)
+                });
+
+                let (gain, heard) = match side {
+                    _ if shared => (Side::Old, side),
+                    Side::Old => (Side::Both, Side::Old),
+                    Side::Both => (Side::Old, Side::Old),
+                    Side::New => (Side::Old, Side::Both),
+                };
+
+                previous.side = gain;
+
+                if !shared {
+                    if let Some(target) = positions.get(&previous.id) {
+                        arriving[*target] = merge_side(arriving[*target], heard);
+                    }
+                }
+            }
+        }
+
+        // Each output's host channel, for matching outputs that are in both
+        // topologies
+        let layout = |outputs: &[Index]| -> Vec<(Option<usize>, usize)> {
+            let mut channel = 0;
+
+            outputs
+                .iter()
+                .map(|id| {
+                    let position = positions.get(id).copied();
+                    let first = channel;
+                    channel += position.map(|i| nodes[i].output_channels).unwrap_or(0);
+                    (position, first)
+                })
+                .collect()
+        };
+
+        let (output_sides, previous_output_sides) = if crossfade {
+            let outputs = layout(&self.outputs);
+            let previous_outputs = layout(&self.previous_outputs);
+
+            let output_sides = outputs
+                .iter()
+                .map(|output| match output {
+                    _ if previous_outputs.contains(output) => Side::New,
+                    (Some(i), _) if sides[*i] == Side::New => Side::Both,
+                    _ => Side::New,
+                })
+                .collect();
+
+            let previous_output_sides = previous_outputs
+                .iter()
+                .map(|output| match output {
+                    _ if outputs.contains(output) => Side::Old,
+                    (Some(i), _) if sides[*i] == Side::Old => Side::Both,
+                    _ => Side::Old,
+                })
+                .collect();
+
+            (output_sides, previous_output_sides)
+        } else {
+            (vec![], vec![])
+        };
+
+        let domains: Vec<(usize, Vec<Port>)> = nodes
+            .iter()
+            .map(|node| (node.rate_divisor, node.input_ports.clone()))
+            .collect();
+
+        for node in nodes.iter_mut() {
+            let from = node.rate_divisor;
+            let output_ports = &node.output_ports;
+
+            for connection in node.connections.iter_mut().chain(node.previous.iter_mut()) {
+                let (to, ports) = &domains[positions[&connection.id]];
+
+                // Only the channels both ports have are mixed
+                let ranges = (
+                    port_range(output_ports, connection.source_port),
+                    port_range(ports, connection.target_port),
+                );
+
+                connection.resampler = match ranges {
+                    (Some((_, sources)), Some((_, targets))) if *to != from => {
+                        Some(Resampler::new(from, *to, sources.min(targets)))
+                    }
+                    _ => None,
+                };
+            }
+        }
+
+        let input_buffers =
+            |node: &TopologyNode<S>| node.input_channels + sidechain_channels(&node.input_ports);
+
+        // The same estimate as `RouteGraph::count_required_temp_buffers`,
+        // for whichever of the orderings needs more
+        let mut buffers: usize = 0;
+
+        for (order, edges) in [(&sorted, &edges), (&fade_sorted, &fade_edges)].iter() {
+            let mut count: usize = 0;
+
+            for i in order.iter() {
+                let node = &nodes[*i];
+
+                count += input_buffers(node)
+                    + edges[*i]
+                        .iter()
+                        .map(|target| input_buffers(&nodes[*target]))
+                        .sum::<usize>();
+                buffers = buffers.max(count);
+                count -= input_buffers(node).min(count);
+            }
+        }
+
+        let max_channels = nodes.iter().fold(0, |a, b| a.max(b.output_channels));
+
+        let mut input_channels: usize = 0;
+
+        for inputs in [&self.inputs, &self.previous_inputs].iter() {
+            input_channels += inputs
+                .iter()
+                .filter_map(|id| positions.get(id))
+                .map(|i| nodes[*i].input_channels)
+                .sum::<usize>();
+        }
+
+        let mut incoming: Vec<Vec<Index>> = vec![vec![]; nodes.len()];
+
+        for node in nodes.iter() {
+            for connection in node.connections.iter() {
+                if let Some(i) = positions.get(&connection.id) {
+                    incoming[*i].push(node.id);
+                }
+            }
+        }
+
+        let pool = BufferPoolBuilder::new()
+            .with_capacity(buffers + max_channels + input_channels)
+            .with_buffer_size(self.buffer_size)
+            .build();
+
+        Ok(Topology {
+            removed_nodes: Vec::with_capacity(removed.len()),
+            nodes: nodes
+                .into_iter()
+                .zip(incoming)
+                .map(|(node, incoming)| SwapNode {
+                    id: node.id,
+                    connections: node.connections,
+                    incoming,
+                    fading: node.previous,
+                })
+                .collect(),
+            removed,
+            ordering,
+            fade_ordering,
+            inputs: self.inputs,
+            outputs: self.outputs,
+            previous_inputs: self.previous_inputs,
+            previous_outputs: self.previous_outputs,
+            output_sides,
+            previous_output_sides,
+            pool,
+            buffer_size: self.buffer_size,
+            crossfade: self.crossfade,
+        })
+    }
+}
+
+/// A sorted set of connections with its buffers already allocated, ready to
+/// be swapped into a graph without allocating.
+///
+/// After a swap the graph hands back the topology it replaced, which holds
+/// the old connections and any removed nodes so they can be dropped away
+/// from the audio thread.
+pub struct Topology<S: Sample + Default, R> {
+    nodes: Vec<SwapNode<S>>,
+    removed: Vec<Index>,
+    removed_nodes: Vec<Node<S, R>>,
+    ordering: Vec<Index>,
+    // The order of the old and new connections together
+    fade_ordering: Vec<Index>,
+    inputs: Vec<Index>,
+    outputs: Vec<Index>,
+    // The old terminals, which are faded out along with the old connections
+    pub(crate) previous_inputs: Vec<Index>,
+    pub(crate) previous_outputs: Vec<Index>,
+    pub(crate) output_sides: Vec<Side>,
+    pub(crate) previous_output_sides: Vec<Side>,
+    pool: BufferPool<S>,
+    buffer_size: usize,
+    crossfade: usize,
+}
+
+// The pool has no outstanding references, so the topology can be built
+// on one thread and swapped in on another.
+unsafe impl<S, R> Send for Topology<S, R>
+where
+    S: Sample + Default,
+    R: Send,
+{
+}
+
+impl<S: Sample + Default, R: Route<S>> Topology<S, R> {
+    /// The nodes that were removed from the graph by this topology. Their
+    /// routes are released here rather than on the audio thread.
+    pub fn into_removed_nodes(mut self) -> Vec<Node<S, R>> {
+        for node in self.removed_nodes.iter_mut() {
+            node.route.release();
+        }
+
+        self.removed_nodes
+    }
+}
+
+impl<S, R, C> RouteGraph<S, R>
+where
+    S: Sample + Default,
+    R: Route<S, Context = C>,
+{
+    /// Take a copy of the graph's connections to edit and swap back in
+    pub fn topology(&self) -> TopologyBuilder<S> {
+        let nodes = self
+            .arena
+            .iter()
+            .map(|(id, node)| TopologyNode {
+                id,
+                input_channels: node.input_channels,
+                output_channels: node.output_channels,
+                input_ports: node.input_ports.clone(),
+                output_ports: node.output_ports.clone(),
+                rate_divisor: node.rate_divisor,
+                connections: node.connections.iter().map(copy_connection).collect(),
+                previous: node.connections.iter().map(copy_connection).collect(),
+            })
+            .collect();
+
+        TopologyBuilder {
+            nodes,
+            removed: vec![],
+            inputs: self.inputs.clone(),
+            outputs: self.outputs.clone(),
+            previous_inputs: self.inputs.clone(),
+            previous_outputs: self.outputs.clone(),
+            buffer_size: self.buffer_size(),
+            crossfade: 0,
+        }
+    }
+
+    /// Swap in a new topology at the start of the next block, reusing the
+    /// routes of the existing nodes. The topology it replaces can be taken
+    /// with `retired_topology` once the swap and any crossfade have finished.
+    /// A swap waits until the last retired topology has been taken, so that
+    /// it's never dropped while processing.
+    ///
+    /// If there's already a topology waiting to be swapped in it's replaced
+    /// and returned.
+    ///
+    /// # Panics
+    /// If the buffer size has changed or nodes have been added since the
+    /// topology was taken
+    pub fn swap_topology(&mut self, topology: Topology<S, R>) -> Option<Topology<S, R>> {
+        assert_eq!(
+            topology.buffer_size,
+            self.buffer_size(),
+            "Buffer size has changed since the topology was taken!"
+        );
+
+        let nodes = topology
+            .nodes
+            .iter()
+            .filter(|node| self.arena.contains(node.id))
+            .count();
+
+        assert!(
+            nodes == self.len()
+                && topology.nodes.iter().all(|node| {
+                    self.arena.contains(node.id) || topology.removed.contains(&node.id)
+                }),
+            "Nodes have changed since the topology was taken!"
+        );
+
+        self.pending_topology.replace(topology)
+    }
+
+    /// The topology that was replaced by the last swap
+    pub fn retired_topology(&mut self) -> Option<Topology<S, R>> {
+        self.retired_topology.take()
+    }
+
+    /// Whether a topology is waiting to be swapped in or being faded in
+    pub fn is_swapping_topology(&self) -> bool {
+        self.pending_topology.is_some() || self.crossfade.is_some()
+    }
+
+    // Swap in the new connections. Until the swap is finished the old
+    // connections are kept alongside them and removed nodes stay in the graph.
+    fn apply_topology(&mut self, mut topology: Topology<S, R>) -> Topology<S, R> {
+        for swap in topology.nodes.iter_mut() {
+            if let Some(node) = self.arena.get_mut(swap.id) {
+                std::mem::swap(&mut node.connections, &mut swap.connections);
+                std::mem::swap(&mut node.incoming, &mut swap.incoming);
+                std::mem::swap(&mut node.fading_connections, &mut swap.fading);
+
+                // Keep any meters on connections that still exist, and the
+                // state of resamplers on the old connections
+                for old in swap.connections.iter_mut() {
+                    let (id, source, target) = (old.id, old.source_port, old.target_port);
+                    let is_same = |c: &&mut Connection<S>| c.is_between(id, source, target);
+
+                    if let Some(connection) = node.connections.iter_mut().find(is_same) {
+                        connection.meter = old.meter.take();
+                    }
+
+                    if let Some(fading) = node.fading_connections.iter_mut().find(is_same) {
+                        if fading.resampler.is_some() && old.resampler.is_some() {
+                            std::mem::swap(&mut fading.resampler, &mut old.resampler);
+                        }
+                    }
+                }
+            }
+        }
+
+        std::mem::swap(&mut self.ordering, &mut topology.fade_ordering);
+        std::mem::swap(&mut self.inputs, &mut topology.inputs);
+        std::mem::swap(&mut self.outputs, &mut topology.outputs);
+        std::mem::swap(&mut self.pool, &mut topology.pool);
+
+        // Signal can still reach the old connections' targets
+        for (_, node) in self.arena.iter_mut() {
+            node.pruned = false;
+        }
+
+        self.sorted = true;
+        self.update_latency();
+
+        topology
+    }
+
+    // Drop the old connections and remove nodes once the crossfade is done
+    fn finish_topology(&mut self, mut topology: Topology<S, R>) -> Topology<S, R> {
+        for swap in topology.nodes.iter_mut() {
+            if let Some(node) = self.arena.get_mut(swap.id) {
+                std::mem::swap(&mut node.fading_connections, &mut swap.fading);
+            }
+        }
+
+        for id in topology.removed.iter() {
+            if let Some(node) = self.arena.remove(*id) {
+                topology.removed_nodes.push(node);
+            }
+        }
+
+        std::mem::swap(&mut self.ordering, &mut topology.ordering);
+
+        self.update_pruning();
+        self.update_latency();
+
+        topology
+    }
+
+    // Called at the start of each block to move any pending topology swap along
+    pub(crate) fn advance_topology(&mut self) {
+        if let Some(crossfade) = &self.crossfade {
+            if crossfade.is_finished() {
+                self.crossfade = None;
+
+                if let Some(topology) = self.fading_topology.take() {
+                    self.retired_topology = Some(self.finish_topology(topology));
+                }
+            }
+
+            return;
+        }
+
+        // Wait for the host to collect the last retired topology, so
+        // it's never dropped on the audio thread
+        if self.retired_topology.is_some() {
+            return;
+        }
+
+        if let Some(topology) = self.pending_topology.take() {
+            let frames = topology.crossfade;
+            let topology = self.apply_topology(topology);
+
+            if frames == 0 {
+                self.retired_topology = Some(self.finish_topology(topology));
+            } else {
+                self.crossfade = Some(Fade::new(frames, true));
+                self.fading_topology = Some(topology);
+            }
+        }
+    }
+}
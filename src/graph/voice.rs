@@ -0,0 +1,524 @@
+use super::{Patch, RouteGraph, SubGraph};
+use crate::convert::to_f64;
+use crate::route::Route;
+use bufferpool::BufferPoolReference;
+use sample::Sample;
+use std::cmp::Ordering;
+use std::ops::Range;
+use std::sync::atomic::{self, AtomicU64, AtomicUsize};
+use std::sync::Arc;
+
+/// A note starting or stopping, passed to a voice group's handler along
+/// with the voice that's playing it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NoteEvent {
+    On { note: u8, velocity: f32 },
+    Off { note: u8 },
+}
+
+impl NoteEvent {
+    // Events are queued as the kind, the note and the velocity's bits
+    fn encode(self) -> u64 {
+        match self {
+            NoteEvent::On { note, velocity } => {
+                1 << 40 | u64::from(note) << 32 | u64::from(velocity.to_bits())
+            }
+            NoteEvent::Off { note } => 2 << 40 | u64::from(note) << 32,
+        }
+    }
+
+    fn decode(event: u64) -> NoteEvent {
+        let note = (event >> 32) as u8;
+
+        match event >> 40 {
+            1 => NoteEvent::On {
+                note,
+                velocity: f32::from_bits(event as u32),
+            },
+            _ => NoteEvent::Off { note },
+        }
+    }
+}
+
+// A ring of events with a single writer and a single reader
+struct NoteQueue {
+    events: Box<[AtomicU64]>,
+    read: AtomicUsize,
+    write: AtomicUsize,
+}
+
+impl NoteQueue {
+    fn new(capacity: usize) -> NoteQueue {
+        NoteQueue {
+            events: (0..capacity).map(|_| AtomicU64::new(0)).collect(),
+            read: AtomicUsize::new(0),
+            write: AtomicUsize::new(0),
+        }
+    }
+
+    fn push(&self, event: NoteEvent) -> Result<(), NoteEvent> {
+        let write = self.write.load(atomic::Ordering::Relaxed);
+        let read = self.read.load(atomic::Ordering::Acquire);
+
+        if write.wrapping_sub(read) >= self.events.len() {
+            return Err(event);
+        }
+
+        self.events[write % self.events.len()].store(event.encode(), atomic::Ordering::Relaxed);
+        self.write
+            .store(write.wrapping_add(1), atomic::Ordering::Release);
+
+        Ok(())
+    }
+
+    fn pop(&self) -> Option<NoteEvent> {
+        let read = self.read.load(atomic::Ordering::Relaxed);
+        let write = self.write.load(atomic::Ordering::Acquire);
+
+        if read == write {
+            return None;
+        }
+
+        let event = self.events[read % self.events.len()].load(atomic::Ordering::Relaxed);
+        self.read
+            .store(read.wrapping_add(1), atomic::Ordering::Release);
+
+        Some(NoteEvent::decode(event))
+    }
+}
+
+/// Sends notes to a voice group from another thread. They're played at the
+/// start of the next block the group processes.
+pub struct NoteSender {
+    queue: Arc<NoteQueue>,
+}
+
+impl NoteSender {
+    /// Queue a note, giving it back if the queue is full
+    pub fn send(&mut self, event: NoteEvent) -> Result<(), NoteEvent> {
+        self.queue.push(event)
+    }
+
+    pub fn note_on(&mut self, note: u8, velocity: f32) -> Result<(), NoteEvent> {
+        self.send(NoteEvent::On { note, velocity })
+    }
+
+    pub fn note_off(&mut self, note: u8) -> Result<(), NoteEvent> {
+        self.send(NoteEvent::Off { note })
+    }
+}
+
+/// Which voice to take for a new note once every voice is playing
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stealing {
+    /// The voice that started playing first
+    Oldest,
+    /// The voice with the lowest peak in the last block
+    Quietest,
+    /// The voice already playing the same note, otherwise the oldest.
+    /// A note that's played again restarts its voice even if others are free.
+    SameNote,
+}
+
+type Handler<S, R> = Box<dyn FnMut(usize, &mut RouteGraph<S, R>, NoteEvent) + Send>;
+
+struct Voice<S: Sample + Default, R> {
+    graph: SubGraph<S, R>,
+    note: u8,
+    // Whether the note is still held down
+    held: bool,
+    // Voices that aren't active are asleep and don't process
+    active: bool,
+    // When the note started, for stealing the oldest voice
+    started: u64,
+    peak: f64,
+}
+
+/// A route that plays notes on a fixed number of voices, each of them a
+/// copy of the same sub graph, and sums their outputs.
+///
+/// Notes are sent with `note_on` and `note_off`, or from another thread
+/// with a `NoteSender`, and the handler tells a voice's graph about them.
+/// A voice that's been released goes to sleep once its output falls below
+/// the silence threshold.
+pub struct VoiceGroup<S: Sample + Default, R> {
+    voices: Vec<Voice<S, R>>,
+    handler: Handler<S, R>,
+    stealing: Stealing,
+    threshold: f64,
+    notes: u64,
+    queue: Option<Arc<NoteQueue>>,
+    channels: usize,
+    max_frames: usize,
+    scratch: Vec<Vec<S>>,
+    input_scratch: Vec<Vec<S>>,
+}
+
+impl<S, R, C> VoiceGroup<S, R>
+where
+    S: Sample + Default,
+    R: Route<S, Context = C>,
+{
+    /// Create a group from its voices, allocating enough space to process
+    /// `max_frames` frames.
+    pub fn new(voices: Vec<SubGraph<S, R>>, max_frames: usize) -> Self {
+        let channels = voices
+            .iter()
+            .filter_map(|voice| {
+                voice
+                    .graph()
+                    .with_node(voice.output(), |node| node.output_channels())
+            })
+            .max()
+            .unwrap_or(0);
+
+        let input_channels = voices
+            .iter()
+            .map(|voice| voice.graph().terminal_channels(&voice.graph().inputs))
+            .max()
+            .unwrap_or(0);
+
+        VoiceGroup {
+            voices: voices
+                .into_iter()
+                .map(|graph| Voice {
+                    graph,
+                    note: 0,
+                    held: false,
+                    active: false,
+                    started: 0,
+                    peak: 0.,
+                })
+                .collect(),
+            handler: Box::new(|_, _, _| {}),
+            stealing: Stealing::Oldest,
+            threshold: 0.0001,
+            notes: 0,
+            queue: None,
+            channels,
+            max_frames,
+            scratch: vec![vec![S::equilibrium(); max_frames]; channels],
+            input_scratch: vec![vec![S::equilibrium(); max_frames]; input_channels],
+        }
+    }
+
+    /// Create a group of `voices` voices, each built from a copy of the patch
+    ///
+    /// # Panics
+    /// If the patch doesn't have an input and an output node
+    pub fn from_patch<P, F>(
+        patch: Patch<S, P>,
+        voices: usize,
+        buffer_size: usize,
+        mut create: F,
+    ) -> Self
+    where
+        P: Clone,
+        F: FnMut(P) -> R,
+    {
+        let voices = (0..voices)
+            .map(|_| SubGraph::from_patch(patch.clone(), buffer_size, &mut create))
+            .collect();
+
+        Self::new(voices, buffer_size)
+    }
+
+    /// Called with the voice and its graph whenever a voice starts or
+    /// stops playing a note. It's called on the audio thread.
+    pub fn with_handler<F>(mut self, handler: F) -> Self
+    where
+        F: FnMut(usize, &mut RouteGraph<S, R>, NoteEvent) + Send + 'static,
+    {
+        self.handler = Box::new(handler);
+        self
+    }
+
+    pub fn with_stealing(mut self, stealing: Stealing) -> Self {
+        self.stealing = stealing;
+        self
+    }
+
+    /// Released voices go to sleep once the peak of their output in a
+    /// block is at or below `threshold`
+    pub fn with_silence_threshold(mut self, threshold: f64) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Create a sender for playing notes from another thread, with room
+    /// for `capacity` notes between blocks. Notes from an earlier sender
+    /// are no longer played.
+    ///
+    /// # Panics
+    /// If `capacity` is zero
+    pub fn note_sender(&mut self, capacity: usize) -> NoteSender {
+        assert!(
+            capacity > 0,
+            "A note queue needs room for at least one note!"
+        );
+
+        let queue = Arc::new(NoteQueue::new(capacity));
+        self.queue = Some(Arc::clone(&queue));
+
+        NoteSender { queue }
+    }
+
+    pub fn voices(&self) -> usize {
+        self.voices.len()
+    }
+
+    /// The number of voices that are playing or still releasing
+    pub fn active_voices(&self) -> usize {
+        self.voices.iter().filter(|voice| voice.active).count()
+    }
+
+    /// The note a voice is playing, if it's active
+    pub fn voice_note(&self, voice: usize) -> Option<u8> {
+        self.voices
+            .get(voice)
+            .filter(|voice| voice.active)
+            .map(|voice| voice.note)
+    }
+
+    // The voice with the lowest key. Released voices are taken before
+    // held ones.
+    fn steal_by<K: PartialOrd>(&self, key: impl Fn(&Voice<S, R>) -> K) -> Option<usize> {
+        let released = self.voices.iter().any(|voice| !voice.held);
+
+        self.voices
+            .iter()
+            .enumerate()
+            .filter(|(_, voice)| !released || !voice.held)
+            .map(|(i, voice)| (i, key(voice)))
+            .min_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(Ordering::Equal))
+            .map(|(i, _)| i)
+    }
+
+    fn allocate(&self, note: u8) -> Option<usize> {
+        if self.stealing == Stealing::SameNote {
+            let playing = self
+                .voices
+                .iter()
+                .position(|voice| voice.active && voice.note == note);
+
+            if playing.is_some() {
+                return playing;
+            }
+        }
+
+        if let Some(voice) = self.voices.iter().position(|voice| !voice.active) {
+            return Some(voice);
+        }
+
+        match self.stealing {
+            Stealing::Quietest => self.steal_by(|voice| voice.peak),
+            Stealing::Oldest | Stealing::SameNote => self.steal_by(|voice| voice.started),
+        }
+    }
+
+    /// Start playing a note, taking a voice from another note if they're
+    /// all playing. Returns the voice that plays it.
+    pub fn note_on(&mut self, note: u8, velocity: f32) -> Option<usize> {
+        let index = self.allocate(note)?;
+        let voice = &mut self.voices[index];
+
+        // A voice taken from another note starts from silent buffers. Its
+        // routes are left for the handler to restart.
+        if voice.active && voice.note != note {
+            voice.graph.graph_mut().pool.clear();
+            voice.peak = 0.;
+        }
+
+        self.notes += 1;
+
+        voice.note = note;
+        voice.held = true;
+        voice.active = true;
+        voice.started = self.notes;
+
+        (self.handler)(
+            index,
+            voice.graph.graph_mut(),
+            NoteEvent::On { note, velocity },
+        );
+
+        Some(index)
+    }
+
+    /// Release every voice playing `note`
+    pub fn note_off(&mut self, note: u8) {
+        for (index, voice) in self.voices.iter_mut().enumerate() {
+            if voice.held && voice.note == note {
+                voice.held = false;
+                (self.handler)(index, voice.graph.graph_mut(), NoteEvent::Off { note });
+            }
+        }
+    }
+
+    /// Release every voice
+    pub fn all_notes_off(&mut self) {
+        for (index, voice) in self.voices.iter_mut().enumerate() {
+            if voice.held {
+                voice.held = false;
+
+                let note = voice.note;
+                (self.handler)(index, voice.graph.graph_mut(), NoteEvent::Off { note });
+            }
+        }
+    }
+
+    // Play the notes sent from other threads since the last block
+    fn play_queued_notes(&mut self) {
+        while let Some(event) = self.queue.as_ref().and_then(|queue| queue.pop()) {
+            match event {
+                NoteEvent::On { note, velocity } => {
+                    self.note_on(note, velocity);
+                }
+                NoteEvent::Off { note } => self.note_off(note),
+            }
+        }
+    }
+
+    fn allocate_scratch(&mut self, channels: usize, max_frames: usize) {
+        let input_channels = self.input_scratch.len();
+
+        self.channels = channels;
+        self.max_frames = max_frames;
+        self.scratch = vec![vec![S::equilibrium(); max_frames]; channels];
+        self.input_scratch = vec![vec![S::equilibrium(); max_frames]; input_channels];
+    }
+}
+
+// Process the active voices for `part` of the block, summing them into
+// `output`
+fn process_voices<S, R, C, I>(
+    voices: &mut [Voice<S, R>],
+    scratch: &mut [Vec<S>],
+    threshold: f64,
+    input: &[I],
+    output: &mut [BufferPoolReference<S>],
+    part: Range<usize>,
+    context: &mut C,
+) where
+    S: Sample + Default,
+    R: Route<S, Context = C>,
+    I: AsRef<[S]>,
+{
+    let frames = part.len();
+
+    for voice in voices.iter_mut().filter(|voice| voice.active) {
+        voice
+            .graph
+            .graph_mut()
+            .process_with_buffers(input, scratch, frames, context);
+
+        let mut peak: f64 = 0.;
+
+        for (output, channel) in output.iter_mut().zip(scratch.iter()) {
+            let output = output.as_mut().iter_mut().skip(part.start).take(frames);
+
+            for (output, sample) in output.zip(channel) {
+                *output = output.add_amp(sample.to_signed_sample());
+                peak = peak.max(to_f64(*sample).abs());
+            }
+        }
+
+        voice.peak = peak;
+
+        if !voice.held && peak <= threshold {
+            voice.active = false;
+        }
+    }
+}
+
+impl<S, R, C> Route<S> for VoiceGroup<S, R>
+where
+    S: Sample + Default,
+    R: Route<S, Context = C>,
+{
+    type Context = C;
+
+    fn process(
+        &mut self,
+        input: &[BufferPoolReference<S>],
+        output: &mut [BufferPoolReference<S>],
+        frames: usize,
+        context: &mut Self::Context,
+    ) {
+        self.play_queued_notes();
+
+        if frames <= self.max_frames {
+            process_voices(
+                &mut self.voices,
+                &mut self.scratch,
+                self.threshold,
+                input,
+                output,
+                0..frames,
+                context,
+            );
+
+            return;
+        }
+
+        // Longer blocks are split into parts that fit in the scratch buffers,
+        // with the input copied over a part at a time
+        let max_frames = self.max_frames;
+
+        for start in (0..frames).step_by(max_frames) {
+            let part = start..(start + max_frames).min(frames);
+
+            for (scratch, input) in self.input_scratch.iter_mut().zip(input) {
+                let input = &input.as_ref()[part.clone()];
+                scratch[..input.len()].copy_from_slice(input);
+            }
+
+            let channels = input.len().min(self.input_scratch.len());
+
+            process_voices(
+                &mut self.voices,
+                &mut self.scratch,
+                self.threshold,
+                &self.input_scratch[..channels],
+                output,
+                part,
+                context,
+            );
+        }
+    }
+
+    fn latency(&self) -> usize {
+        self.voices
+            .iter()
+            .map(|voice| voice.graph.latency())
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn prepare(&mut self, sample_rate: f64, max_block: usize, channels: usize) {
+        let channels = channels.max(self.channels);
+
+        if max_block > self.max_frames || channels > self.scratch.len() {
+            self.allocate_scratch(channels, max_block.max(self.max_frames));
+        }
+
+        for voice in self.voices.iter_mut() {
+            voice.graph.prepare(sample_rate, max_block, channels);
+        }
+    }
+
+    fn reset(&mut self) {
+        for voice in self.voices.iter_mut() {
+            voice.graph.reset();
+            voice.held = false;
+            voice.active = false;
+            voice.peak = 0.;
+        }
+    }
+
+    fn release(&mut self) {
+        for voice in self.voices.iter_mut() {
+            voice.graph.release();
+        }
+    }
+}
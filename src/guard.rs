@@ -0,0 +1,225 @@
+use generational_arena::Index;
+use std::alloc::{GlobalAlloc, Layout};
+use std::cell::Cell;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+/// What to do when a route allocates or deallocates while processing
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GuardMode {
+    /// Record the offending node in the graph's `GuardReport`, to be read
+    /// from another thread
+    Log,
+    Panic,
+}
+
+#[derive(Clone, Copy, Default)]
+struct State {
+    active: bool,
+    allocations: usize,
+    deallocations: usize,
+}
+
+thread_local! {
+    static STATE: Cell<State> = Cell::new(State::default());
+}
+
+fn record(deallocation: bool) {
+    let _ = STATE.try_with(|state| {
+        let mut current = state.get();
+
+        if current.active {
+            if deallocation {
+                current.deallocations += 1;
+            } else {
+                current.allocations += 1;
+            }
+
+            state.set(current);
+        }
+    });
+}
+
+/// A global allocator that counts heap allocations made while a graph is
+/// processing. It needs to be installed for the guard to detect anything:
+///
+/// ```ignore
+/// #[global_allocator]
+/// static ALLOCATOR: GuardedAllocator<System> = GuardedAllocator::new(System);
+/// ```
+pub struct GuardedAllocator<A> {
+    inner: A,
+}
+
+impl<A> GuardedAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        GuardedAllocator { inner }
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for GuardedAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        record(false);
+        self.inner.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        record(true);
+        self.inner.dealloc(ptr, layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        record(false);
+        self.inner.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        record(false);
+        self.inner.realloc(ptr, layout, new_size)
+    }
+}
+
+/// An allocation made while processing
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Violation {
+    /// The node that allocated, or `None` if it was the graph itself
+    pub node: Option<Index>,
+    pub allocations: usize,
+    pub deallocations: usize,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} allocations and {} deallocations while processing ",
+            self.allocations, self.deallocations
+        )?;
+
+        match self.node {
+            Some(node) => write!(f, "node {:?}", node),
+            None => write!(f, "the graph"),
+        }
+    }
+}
+
+/// The latest allocation the guard caught in `GuardMode::Log`. It's written
+/// on the audio thread without allocating, so it can be read and logged
+/// from another thread.
+#[derive(Default)]
+pub struct GuardReport {
+    // Incremented after the rest of the violation is written
+    violations: AtomicUsize,
+    // The last violation that was read
+    seen: AtomicUsize,
+    has_node: AtomicBool,
+    node_index: AtomicUsize,
+    node_generation: AtomicU64,
+    allocations: AtomicUsize,
+    deallocations: AtomicUsize,
+}
+
+impl GuardReport {
+    fn record(&self, violation: Violation) {
+        if let Some(node) = violation.node {
+            let (index, generation) = node.into_raw_parts();
+            self.node_index.store(index, Ordering::Relaxed);
+            self.node_generation.store(generation, Ordering::Relaxed);
+        }
+
+        self.has_node
+            .store(violation.node.is_some(), Ordering::Relaxed);
+        self.allocations
+            .store(violation.allocations, Ordering::Relaxed);
+        self.deallocations
+            .store(violation.deallocations, Ordering::Relaxed);
+        self.violations.fetch_add(1, Ordering::Release);
+    }
+
+    /// The number of times processing has allocated
+    pub fn violations(&self) -> usize {
+        self.violations.load(Ordering::Acquire)
+    }
+
+    /// The latest violation, if there's been one since it was last taken
+    pub fn take(&self) -> Option<Violation> {
+        let violations = self.violations();
+
+        if self.seen.swap(violations, Ordering::Relaxed) == violations {
+            return None;
+        }
+
+        let node = if self.has_node.load(Ordering::Relaxed) {
+            Some(Index::from_raw_parts(
+                self.node_index.load(Ordering::Relaxed),
+                self.node_generation.load(Ordering::Relaxed),
+            ))
+        } else {
+            None
+        };
+
+        Some(Violation {
+            node,
+            allocations: self.allocations.load(Ordering::Relaxed),
+            deallocations: self.deallocations.load(Ordering::Relaxed),
+        })
+    }
+}
+
+/// Watches for allocations on the current thread until it's finished.
+/// Allocations made inside a nested guard are only reported by that guard.
+pub(crate) struct Guard {
+    node: Option<Index>,
+    mode: GuardMode,
+    previous: State,
+}
+
+impl Guard {
+    pub(crate) fn enter(node: Option<Index>, mode: GuardMode) -> Guard {
+        let previous = STATE.with(|state| {
+            state.replace(State {
+                active: true,
+                ..State::default()
+            })
+        });
+
+        Guard {
+            node,
+            mode,
+            previous,
+        }
+    }
+
+    /// Stop watching and report anything that was allocated
+    pub(crate) fn finish(self, report: &GuardReport) {
+        let current = STATE.with(|state| state.get());
+
+        let violation = Violation {
+            node: self.node,
+            allocations: current.allocations,
+            deallocations: current.deallocations,
+        };
+
+        let mode = self.mode;
+
+        // Restore the previous state before reporting, since panicking
+        // can allocate.
+        drop(self);
+
+        if violation.allocations == 0 && violation.deallocations == 0 {
+            return;
+        }
+
+        match mode {
+            GuardMode::Log => report.record(violation),
+            GuardMode::Panic => panic!("{}", violation),
+        }
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let previous = self.previous;
+        STATE.with(|state| state.set(previous));
+    }
+}
@@ -1,9 +1,17 @@
 extern crate sample;
 
+pub mod convert;
 pub mod graph;
+#[cfg(feature = "rt-guard")]
+pub mod guard;
+pub mod oversample;
 pub mod route;
 
 pub use bufferpool::BufferPoolReference;
+pub use convert::*;
 pub use generational_arena::Index;
 pub use graph::*;
+#[cfg(feature = "rt-guard")]
+pub use guard::*;
+pub use oversample::*;
 pub use route::*;
@@ -0,0 +1,588 @@
+use crate::convert::{from_f64, to_f64};
+use crate::route::{Route, Tail};
+use bufferpool::{BufferPool, BufferPoolBuilder, BufferPoolReference};
+use sample::Sample;
+
+// The half band filters have 31 taps. Every other tap is zero except for
+// the center one, so only the 16 even taps need to be stored.
+const TAPS: usize = 31;
+const CENTER: usize = TAPS / 2;
+const EVEN_TAPS: usize = TAPS / 2 + 1;
+const ODD_DELAY: usize = CENTER / 2 + 1;
+
+/// How many times faster an oversampled route is run
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Oversampling {
+    X2,
+    X4,
+    X8,
+}
+
+impl Oversampling {
+    pub fn factor(self) -> usize {
+        1 << self.stages()
+    }
+
+    fn stages(self) -> usize {
+        match self {
+            Oversampling::X2 => 1,
+            Oversampling::X4 => 2,
+            Oversampling::X8 => 3,
+        }
+    }
+}
+
+// The even taps of a windowed sinc half band filter, normalised so that
+// the filter has unity gain at DC.
+fn half_band_taps() -> [f64; EVEN_TAPS] {
+    let mut taps = [0.; EVEN_TAPS];
+
+    for (j, tap) in taps.iter_mut().enumerate() {
+        let n = 2 * j;
+        let x = (n as f64 - CENTER as f64) / 2.;
+        let sinc = (std::f64::consts::PI * x).sin() / (std::f64::consts::PI * x);
+        let phase = 2. * std::f64::consts::PI * n as f64 / (TAPS - 1) as f64;
+        let window = 0.42 - 0.5 * phase.cos() + 0.08 * (2. * phase).cos();
+        *tap = sinc * window;
+    }
+
+    let sum: f64 = taps.iter().sum();
+
+    for tap in taps.iter_mut() {
+        *tap *= 0.5 / sum;
+    }
+
+    taps
+}
+
+#[derive(Clone)]
+struct Upsampler {
+    history: [f64; EVEN_TAPS],
+    position: usize,
+}
+
+impl Upsampler {
+    fn new() -> Upsampler {
+        Upsampler {
+            history: [0.; EVEN_TAPS],
+            position: 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.history = [0.; EVEN_TAPS];
+        self.position = 0;
+    }
+
+    fn process(&mut self, taps: &[f64; EVEN_TAPS], input: &[f64], output: &mut [f64]) {
+        for (sample, output) in input.iter().zip(output.chunks_mut(2)) {
+            self.position = (self.position + EVEN_TAPS - 1) % EVEN_TAPS;
+            self.history[self.position] = *sample;
+
+            let mut even = 0.;
+            for (j, tap) in taps.iter().enumerate() {
+                even += tap * self.history[(self.position + j) % EVEN_TAPS];
+            }
+
+            output[0] = 2. * even;
+            if let Some(odd) = output.get_mut(1) {
+                *odd = self.history[(self.position + CENTER / 2) % EVEN_TAPS];
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Downsampler {
+    even: [f64; EVEN_TAPS],
+    odd: [f64; ODD_DELAY],
+    even_position: usize,
+    odd_position: usize,
+}
+
+impl Downsampler {
+    fn new() -> Downsampler {
+        Downsampler {
+            even: [0.; EVEN_TAPS],
+            odd: [0.; ODD_DELAY],
+            even_position: 0,
+            odd_position: 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.even = [0.; EVEN_TAPS];
+        self.odd = [0.; ODD_DELAY];
+        self.even_position = 0;
+        self.odd_position = 0;
+    }
+
+    fn process(&mut self, taps: &[f64; EVEN_TAPS], input: &[f64], output: &mut [f64]) {
+        for (pair, output) in input.chunks(2).zip(output.iter_mut()) {
+            self.even_position = (self.even_position + EVEN_TAPS - 1) % EVEN_TAPS;
+            self.even[self.even_position] = pair[0];
+
+            let mut sum = 0.5 * self.odd[self.odd_position];
+            for (j, tap) in taps.iter().enumerate() {
+                sum += tap * self.even[(self.even_position + j) % EVEN_TAPS];
+            }
+
+            *output = sum;
+
+            self.odd[self.odd_position] = pair.get(1).copied().unwrap_or(0.);
+            self.odd_position = (self.odd_position + 1) % ODD_DELAY;
+        }
+    }
+}
+
+/// A route wrapper that runs the inner route at a higher sample rate,
+/// using half band filters to upsample the input and downsample the
+/// output. Useful for nonlinear routes that would otherwise alias.
+pub struct Oversampled<S: Sample + Default, R> {
+    route: R,
+    oversampling: Oversampling,
+    channels: usize,
+    sidechain: usize,
+    max_frames: usize,
+    taps: [f64; EVEN_TAPS],
+    upsamplers: Vec<Vec<Upsampler>>,
+    downsamplers: Vec<Vec<Downsampler>>,
+    // Delays the oversampled output so the total latency is a whole
+    // number of frames at the original rate.
+    delays: Vec<Vec<f64>>,
+    delay_position: usize,
+    scratch: (Vec<f64>, Vec<f64>),
+    // The upsampled inputs followed by the upsampled sidechain
+    input: Vec<BufferPoolReference<S>>,
+    output: Vec<BufferPoolReference<S>>,
+    // Keep the pool around for as long as the references
+    pool: BufferPool<S>,
+}
+
+// The buffer pool references all belong to the pool that's owned
+// by the route, so it's safe to move between threads with it.
+unsafe impl<S, R> Send for Oversampled<S, R>
+where
+    S: Sample + Default,
+    R: Send,
+{
+}
+
+impl<S, R> Oversampled<S, R>
+where
+    S: Sample + Default,
+    R: Route<S>,
+{
+    /// Wrap `route`, allocating enough space to process `max_frames`
+    /// frames at the original rate.
+    pub fn new(route: R, oversampling: Oversampling, channels: usize, max_frames: usize) -> Self {
+        let factor = oversampling.factor();
+        let stages = oversampling.stages();
+
+        let buffer_size = max_frames * factor;
+
+        let mut pool = BufferPoolBuilder::new()
+            .with_capacity(channels * 2)
+            .with_buffer_size(buffer_size)
+            .build();
+
+        let input = (0..channels)
+            .map(|_| pool.get_cleared_space().unwrap())
+            .collect();
+        let output = (0..channels)
+            .map(|_| pool.get_cleared_space().unwrap())
+            .collect();
+
+        let filter_delay = 2 * CENTER * (factor - 1);
+        let padding = (factor - filter_delay % factor) % factor;
+
+        Oversampled {
+            route,
+            oversampling,
+            channels,
+            sidechain: 0,
+            max_frames,
+            taps: half_band_taps(),
+            upsamplers: vec![vec![Upsampler::new(); stages]; channels],
+            downsamplers: vec![vec![Downsampler::new(); stages]; channels],
+            delays: vec![vec![0.; padding]; channels],
+            delay_position: 0,
+            scratch: (vec![0.; buffer_size], vec![0.; buffer_size]),
+            input,
+            output,
+            pool,
+        }
+    }
+
+    /// Oversample a sidechain with `channels` channels for the inner route
+    pub fn with_sidechain(mut self, channels: usize) -> Self {
+        let stages = self.oversampling.stages();
+
+        self.sidechain = channels;
+        self.upsamplers = vec![vec![Upsampler::new(); stages]; self.channels + channels];
+        self.allocate(self.max_frames);
+        self
+    }
+
+    pub fn oversampling(&self) -> Oversampling {
+        self.oversampling
+    }
+
+    pub fn route(&mut self) -> &mut R {
+        &mut self.route
+    }
+
+    pub fn into_inner(self) -> R {
+        self.route
+    }
+
+    fn allocate(&mut self, max_frames: usize) {
+        let buffer_size = max_frames * self.oversampling.factor();
+
+        self.input.clear();
+        self.output.clear();
+
+        self.pool = BufferPoolBuilder::new()
+            .with_capacity(self.channels * 2 + self.sidechain)
+            .with_buffer_size(buffer_size)
+            .build();
+
+        for _ in 0..self.channels + self.sidechain {
+            self.input.push(self.pool.get_cleared_space().unwrap());
+        }
+
+        for _ in 0..self.channels {
+            self.output.push(self.pool.get_cleared_space().unwrap());
+        }
+
+        self.scratch = (vec![0.; buffer_size], vec![0.; buffer_size]);
+        self.max_frames = max_frames;
+    }
+
+    // Upsample the inputs and then the sidechain into the inner buffers
+    fn upsample(
+        &mut self,
+        input: &[BufferPoolReference<S>],
+        sidechain: &[BufferPoolReference<S>],
+        frames: usize,
+    ) {
+        let channels = self.channels;
+        let (a, b) = &mut self.scratch;
+
+        for (channel, inner) in self.input.iter_mut().enumerate() {
+            let outer = if channel < channels {
+                input.get(channel)
+            } else {
+                sidechain.get(channel - channels)
+            };
+
+            if let Some(outer) = outer {
+                for (value, sample) in a.iter_mut().zip(outer.as_ref()).take(frames) {
+                    *value = to_f64(*sample);
+                }
+            } else {
+                for value in a.iter_mut().take(frames) {
+                    *value = 0.;
+                }
+            }
+
+            let mut len = frames;
+
+            for upsampler in self.upsamplers[channel].iter_mut() {
+                upsampler.process(&self.taps, &a[..len], &mut b[..len * 2]);
+                std::mem::swap(a, b);
+                len *= 2;
+            }
+
+            for (sample, value) in inner.as_mut().iter_mut().zip(a.iter()).take(len) {
+                *sample = from_f64(*value);
+            }
+        }
+    }
+
+    // Downsample the inner output, `frames` is at the original rate
+    fn downsample(&mut self, output: &mut [BufferPoolReference<S>], frames: usize) {
+        let factor = self.oversampling.factor();
+        let (a, b) = &mut self.scratch;
+
+        let delay_position = self.delay_position;
+
+        for (channel, inner) in self.output.iter().enumerate() {
+            let mut len = frames * factor;
+
+            let delay = &mut self.delays[channel];
+            let mut position = delay_position;
+
+            for (value, sample) in a.iter_mut().zip(inner.as_ref()).take(len) {
+                *value = to_f64(*sample);
+
+                if !delay.is_empty() {
+                    std::mem::swap(value, &mut delay[position]);
+                    position = (position + 1) % delay.len();
+                }
+            }
+
+            self.delay_position = position;
+
+            for downsampler in self.downsamplers[channel].iter_mut().rev() {
+                downsampler.process(&self.taps, &a[..len], &mut b[..len / 2]);
+                std::mem::swap(a, b);
+                len /= 2;
+            }
+
+            if let Some(output) = output.get_mut(channel) {
+                for (sample, value) in output.as_mut().iter_mut().zip(a.iter()).take(frames) {
+                    *sample = from_f64(*value);
+                }
+            }
+        }
+    }
+
+    // The latency of the filters and padding at the original rate
+    fn filter_latency(&self) -> usize {
+        let factor = self.oversampling.factor();
+        let padding = self.delays.first().map(|delay| delay.len()).unwrap_or(0);
+        (2 * CENTER * (factor - 1) + padding) / factor
+    }
+}
+
+impl<S, R, C> Route<S> for Oversampled<S, R>
+where
+    S: Sample + Default,
+    R: Route<S, Context = C>,
+{
+    type Context = C;
+
+    fn process(
+        &mut self,
+        input: &[BufferPoolReference<S>],
+        output: &mut [BufferPoolReference<S>],
+        frames: usize,
+        context: &mut Self::Context,
+    ) {
+        let frames = frames.min(self.max_frames);
+        let factor = self.oversampling.factor();
+
+        self.upsample(input, &[], frames);
+
+        self.route.process(
+            &self.input[..input.len().min(self.channels)],
+            &mut self.output,
+            frames * factor,
+            context,
+        );
+
+        self.downsample(output, frames);
+    }
+
+    fn process_sidechain(
+        &mut self,
+        input: &[BufferPoolReference<S>],
+        sidechain: &[BufferPoolReference<S>],
+        output: &mut [BufferPoolReference<S>],
+        frames: usize,
+        context: &mut Self::Context,
+    ) {
+        let frames = frames.min(self.max_frames);
+        let factor = self.oversampling.factor();
+
+        self.upsample(input, sidechain, frames);
+
+        let (inner, inner_sidechain) = self.input.split_at(self.channels);
+
+        self.route.process_sidechain(
+            &inner[..input.len().min(self.channels)],
+            &inner_sidechain[..sidechain.len().min(self.sidechain)],
+            &mut self.output,
+            frames * factor,
+            context,
+        );
+
+        self.downsample(output, frames);
+    }
+
+    fn latency(&self) -> usize {
+        let factor = self.oversampling.factor();
+        self.filter_latency() + (self.route.latency() + factor / 2) / factor
+    }
+
+    fn tail(&self) -> Tail {
+        match self.route.tail() {
+            Tail::Frames(frames) => {
+                Tail::Frames(self.filter_latency() + frames / self.oversampling.factor() + 1)
+            }
+            Tail::Infinite => Tail::Infinite,
+        }
+    }
+
+    fn prepare(&mut self, sample_rate: f64, max_block: usize, channels: usize) {
+        if max_block > self.max_frames {
+            self.allocate(max_block);
+        }
+
+        let factor = self.oversampling.factor();
+
+        self.route
+            .prepare(sample_rate * factor as f64, max_block * factor, channels);
+    }
+
+    fn reset(&mut self) {
+        for upsampler in self.upsamplers.iter_mut().flatten() {
+            upsampler.reset();
+        }
+
+        for downsampler in self.downsamplers.iter_mut().flatten() {
+            downsampler.reset();
+        }
+
+        for delay in self.delays.iter_mut() {
+            for sample in delay.iter_mut() {
+                *sample = 0.;
+            }
+        }
+
+        self.delay_position = 0;
+
+        self.route.reset();
+    }
+
+    fn release(&mut self) {
+        self.route.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc_counter::deny_alloc;
+
+    struct PassThrough;
+
+    impl Route<f32> for PassThrough {
+        type Context = ();
+
+        fn process(
+            &mut self,
+            input: &[BufferPoolReference<f32>],
+            output: &mut [BufferPoolReference<f32>],
+            frames: usize,
+            _context: &mut Self::Context,
+        ) {
+            for (output, input) in output.iter_mut().zip(input.iter()) {
+                for (output, input) in output.as_mut().iter_mut().zip(input.as_ref()).take(frames) {
+                    *output = *input;
+                }
+            }
+        }
+    }
+
+    // Outputs its sidechain
+    struct Key;
+
+    impl Route<f32> for Key {
+        type Context = ();
+
+        fn process(
+            &mut self,
+            input: &[BufferPoolReference<f32>],
+            output: &mut [BufferPoolReference<f32>],
+            frames: usize,
+            context: &mut Self::Context,
+        ) {
+            self.process_sidechain(input, &[], output, frames, context);
+        }
+
+        fn process_sidechain(
+            &mut self,
+            _input: &[BufferPoolReference<f32>],
+            sidechain: &[BufferPoolReference<f32>],
+            output: &mut [BufferPoolReference<f32>],
+            frames: usize,
+            _context: &mut Self::Context,
+        ) {
+            for (i, sample) in output[0].as_mut().iter_mut().take(frames).enumerate() {
+                *sample = sidechain.first().map_or(0., |key| key.as_ref()[i]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_oversampled_impulse_is_delayed_by_latency() {
+        for oversampling in [Oversampling::X2, Oversampling::X4, Oversampling::X8].iter() {
+            let mut route = Oversampled::new(PassThrough, *oversampling, 1, 64);
+            let latency = route.latency();
+
+            let mut pool: BufferPool<f32> = BufferPoolBuilder::new()
+                .with_capacity(2)
+                .with_buffer_size(64)
+                .build();
+
+            let mut input = pool.get_cleared_space().unwrap();
+            let mut output = [pool.get_cleared_space().unwrap()];
+
+            input.as_mut()[0] = 1.;
+
+            route.process(&[input], &mut output, 64, &mut ());
+
+            let output = output[0].as_ref();
+            let peak = (0..64)
+                .max_by(|a, b| output[*a].abs().partial_cmp(&output[*b].abs()).unwrap())
+                .unwrap();
+
+            assert_eq!(peak, latency);
+            assert!((output.iter().sum::<f32>() - 1.).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn test_oversampled_reset_clears_filters_in_place() {
+        let mut route = Oversampled::new(PassThrough, Oversampling::X4, 1, 64);
+
+        let mut pool: BufferPool<f32> = BufferPoolBuilder::new()
+            .with_capacity(2)
+            .with_buffer_size(64)
+            .build();
+
+        let mut input = [pool.get_cleared_space().unwrap()];
+        let mut output = [pool.get_cleared_space().unwrap()];
+
+        input[0].as_mut()[0] = 1.;
+
+        route.process(&input, &mut output, 64, &mut ());
+        let first = output[0].as_ref().to_vec();
+
+        // Leave some of the impulse in the filters
+        route.process(&input, &mut output, 8, &mut ());
+
+        deny_alloc(|| route.reset());
+
+        route.process(&input, &mut output, 64, &mut ());
+
+        assert_eq!(output[0].as_ref(), &first[..]);
+    }
+
+    #[test]
+    fn test_oversampled_sidechain() {
+        let mut through = Oversampled::new(PassThrough, Oversampling::X2, 1, 64);
+        let mut keyed = Oversampled::new(Key, Oversampling::X2, 1, 64).with_sidechain(1);
+
+        let mut pool: BufferPool<f32> = BufferPoolBuilder::new()
+            .with_capacity(4)
+            .with_buffer_size(64)
+            .build();
+
+        let input = [pool.get_cleared_space().unwrap()];
+        let mut key = [pool.get_cleared_space().unwrap()];
+        let mut expected = [pool.get_cleared_space().unwrap()];
+        let mut output = [pool.get_cleared_space().unwrap()];
+
+        key[0].as_mut()[0] = 1.;
+
+        through.process(&key, &mut expected, 64, &mut ());
+
+        // The key goes through the same filters as the input
+        deny_alloc(|| keyed.process_sidechain(&input, &key, &mut output, 64, &mut ()));
+
+        assert_eq!(output[0].as_ref(), expected[0].as_ref());
+    }
+}
@@ -1,6 +1,14 @@
 use bufferpool::BufferPoolReference;
 use sample::Sample;
 
+/// How long a route keeps producing output after its input goes silent
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Tail {
+    Frames(usize),
+    /// The route can produce output without any input, so it never sleeps
+    Infinite,
+}
+
 pub trait Route<S: Sample> {
     type Context;
 
@@ -11,6 +19,51 @@ pub trait Route<S: Sample> {
         frames: usize,
         context: &mut Self::Context,
     );
+
+    /// Process a node that has sidechain inputs, which are passed
+    /// separately from its other inputs. By default the sidechain is
+    /// ignored.
+    fn process_sidechain(
+        &mut self,
+        input: &[BufferPoolReference<S>],
+        _sidechain: &[BufferPoolReference<S>],
+        output: &mut [BufferPoolReference<S>],
+        frames: usize,
+        context: &mut Self::Context,
+    ) {
+        self.process(input, output, frames, context);
+    }
+
+    /// Whether `process` always writes every sample of its output buffers.
+    /// If it doesn't, the graph clears the output before each call so
+    /// nothing is left over from the previous node.
+    fn overwrites_output(&self) -> bool {
+        false
+    }
+
+    /// The number of frames of delay that this route adds to the signal
+    fn latency(&self) -> usize {
+        0
+    }
+
+    /// Once the input has been silent for longer than the tail the graph
+    /// stops processing the route until the input is no longer silent.
+    /// The tail should include the route's latency.
+    fn tail(&self) -> Tail {
+        Tail::Infinite
+    }
+
+    /// Called when the route is added to a graph and whenever the sample
+    /// rate or block size changes. `max_block` is the most frames that
+    /// `process` will be asked for, and `channels` is the number of output
+    /// channels.
+    fn prepare(&mut self, _sample_rate: f64, _max_block: usize, _channels: usize) {}
+
+    /// Clear any state, like delay lines, without freeing memory
+    fn reset(&mut self) {}
+
+    /// Called when the route is removed from a graph
+    fn release(&mut self) {}
 }
 
 impl<S: Sample, C> Route<S> for Box<dyn Route<S, Context = C>> {
@@ -25,4 +78,40 @@ impl<S: Sample, C> Route<S> for Box<dyn Route<S, Context = C>> {
     ) {
         self.as_mut().process(input, output, frames, context);
     }
+
+    fn process_sidechain(
+        &mut self,
+        input: &[BufferPoolReference<S>],
+        sidechain: &[BufferPoolReference<S>],
+        output: &mut [BufferPoolReference<S>],
+        frames: usize,
+        context: &mut Self::Context,
+    ) {
+        self.as_mut()
+            .process_sidechain(input, sidechain, output, frames, context);
+    }
+
+    fn overwrites_output(&self) -> bool {
+        self.as_ref().overwrites_output()
+    }
+
+    fn latency(&self) -> usize {
+        self.as_ref().latency()
+    }
+
+    fn tail(&self) -> Tail {
+        self.as_ref().tail()
+    }
+
+    fn prepare(&mut self, sample_rate: f64, max_block: usize, channels: usize) {
+        self.as_mut().prepare(sample_rate, max_block, channels);
+    }
+
+    fn reset(&mut self) {
+        self.as_mut().reset();
+    }
+
+    fn release(&mut self) {
+        self.as_mut().release();
+    }
 }